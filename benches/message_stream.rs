@@ -0,0 +1,160 @@
+//! Decodes a burst of data messages, like the backlog flushed right after login, and reports the
+//! time and heap allocations spent per message.
+//!
+//! Run with `cargo bench --bench message_stream`.
+
+use bytes::BufMut;
+use fcm_push_listener::{Decryption, Message, MessageStream, WebPushKeys};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The parts of `DataMessageStanza` from mcs.proto that FCM fills in
+#[derive(Clone, PartialEq, prost::Message)]
+struct DataMessageStanza {
+    #[prost(string, required, tag = "3")]
+    from: String,
+    #[prost(string, required, tag = "5")]
+    category: String,
+    #[prost(message, repeated, tag = "7")]
+    app_data: Vec<AppData>,
+    #[prost(string, optional, tag = "9")]
+    persistent_id: Option<String>,
+    #[prost(bytes = "vec", optional, tag = "21")]
+    raw_data: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct AppData {
+    #[prost(string, required, tag = "1")]
+    key: String,
+    #[prost(string, required, tag = "2")]
+    value: String,
+}
+
+const MESSAGES: usize = 2_000;
+const DATA_MESSAGE_STANZA_TAG: u8 = 8;
+const PAYLOAD: &[u8] = br#"{"data":{"myProp":"myValue"},"from":"1001234567890","priority":"normal","fcmMessageId":"2cca9428-b164-401c-be3b-e01d8bce6dcd"}"#;
+
+fn new_keys() -> WebPushKeys {
+    let (key_pair, auth_secret) = ece::generate_keypair_and_auth_secret().unwrap();
+    let components = key_pair.raw_components().unwrap();
+    WebPushKeys {
+        public_key: components.public_key().into(),
+        private_key: components.private_key().into(),
+        auth_secret: auth_secret.into(),
+    }
+}
+
+fn new_stanza(keys: &WebPushKeys, id: usize, encrypted: bool) -> DataMessageStanza {
+    use base64::engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD};
+    use base64::Engine;
+
+    let mut stanza = DataMessageStanza {
+        from: "1001234567890".into(),
+        category: "org.chromium.linux".into(),
+        persistent_id: Some(format!("0:{id}%7031b2e6f9fd7ecd")),
+        ..Default::default()
+    };
+
+    if !encrypted {
+        stanza.app_data.push(AppData {
+            key: "myProp".into(),
+            value: "myValue".into(),
+        });
+        stanza.raw_data = Some(PAYLOAD.to_vec());
+        return stanza;
+    }
+
+    let block = ece::legacy::encrypt_aesgcm(&keys.public_key, &keys.auth_secret, PAYLOAD).unwrap();
+
+    // The headers come unpadded and with a record size, FCM sends neither
+    let reencode = |header: &str| URL_SAFE.encode(URL_SAFE_NO_PAD.decode(header).unwrap());
+    for (name, value) in block.headers(None) {
+        let (key, param, value) = match name {
+            "Crypto-Key" => ("crypto-key", "dh=", value.trim_start_matches("dh=")),
+            _ => ("encryption", "salt=", value.trim_start_matches("salt=")),
+        };
+        let value = value.split(';').next().unwrap();
+        stanza.app_data.push(AppData {
+            key: key.into(),
+            value: format!("{param}{}", reencode(value)),
+        });
+    }
+
+    stanza.raw_data = Some(URL_SAFE_NO_PAD.decode(block.body()).unwrap());
+    stanza
+}
+
+fn run(name: &str, keys: &WebPushKeys, encrypted: bool, decryption: Decryption) {
+    use tokio_stream::StreamExt;
+
+    let mut wire = Vec::new();
+    for id in 0..MESSAGES {
+        let stanza = new_stanza(keys, id, encrypted);
+        wire.put_u8(DATA_MESSAGE_STANZA_TAG);
+        prost::Message::encode_length_delimited(&stanza, &mut wire).unwrap();
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let mut stream =
+            MessageStream::new(std::io::Cursor::new(wire), keys).with_decryption(decryption);
+
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let mut received = 0;
+        while let Some(message) = stream.next().await {
+            if let Message::Data(data) = message.unwrap() {
+                // offloaded decryption must not reorder messages
+                let id = data.persistent_id.as_deref().unwrap();
+                assert_eq!(id[2..id.find('%').unwrap()].parse(), Ok(received));
+                received += 1;
+            }
+        }
+
+        let elapsed = start.elapsed();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        assert_eq!(received, MESSAGES);
+        println!(
+            "{name}: {:.1} us/message, {:.1} allocations/message",
+            elapsed.as_secs_f64() * 1e6 / MESSAGES as f64,
+            allocations as f64 / MESSAGES as f64,
+        );
+    });
+}
+
+fn main() {
+    let keys = new_keys();
+    let offloaded = Decryption::Blocking(std::num::NonZeroUsize::new(4).unwrap());
+    run("encrypted", &keys, true, Decryption::Inline);
+    run("encrypted, blocking pool", &keys, true, offloaded);
+    run("unencrypted", &keys, false, Decryption::Inline);
+}
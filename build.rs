@@ -1,9 +1,12 @@
 extern crate prost_build;
 
 fn main() {
-    prost_build::compile_protos(
-        &["src/proto/checkin.proto", "src/proto/mcs.proto"],
-        &["src/proto"],
-    )
-    .unwrap();
+    prost_build::Config::new()
+        // lets message payloads share the receive buffer instead of being copied out of it
+        .bytes([".mcs_proto.DataMessageStanza.raw_data"])
+        .compile_protos(
+            &["src/proto/checkin.proto", "src/proto/mcs.proto"],
+            &["src/proto"],
+        )
+        .unwrap();
 }
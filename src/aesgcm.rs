@@ -0,0 +1,318 @@
+//! Decryption of the `aesgcm` content encoding from draft 04 of the web push encryption spec,
+//! which FCM still uses for web push messages.
+
+use crate::{Error, KeyAgreement};
+use ece::Error as EceError;
+use ring::{aead, hkdf};
+use zeroize::Zeroizing;
+
+// The record size default is 4096 and doesn't seem to be overridden for FCM.
+const RECORD_SIZE: usize = 4096;
+const PUBLIC_KEY_LEN: usize = 65;
+const AUTH_SECRET_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+const PAD_SIZE: usize = 2;
+const OPERATION: &str = "message decryption";
+
+/// How many bytes of key material to expand to
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[&[u8]], out: &mut [u8]) {
+    hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+        .extract(ikm)
+        .expand(info, Len(out.len()))
+        .and_then(|okm| okm.fill(out))
+        .expect("output length should be within the HKDF limit");
+}
+
+/// decrypts a single record payload, returning the plaintext range of `buffer`
+pub(crate) fn decrypt(
+    agreement: &dyn KeyAgreement,
+    public_key: &[u8],
+    auth_secret: &[u8],
+    sender_public_key: &[u8],
+    salt: &[u8],
+    buffer: &mut [u8],
+) -> Result<std::ops::Range<usize>, Error> {
+    let crypto = |e| Error::Crypto(OPERATION, e);
+
+    if auth_secret.len() != AUTH_SECRET_LEN {
+        return Err(crypto(EceError::InvalidAuthSecret));
+    }
+    if salt.len() != SALT_LEN {
+        return Err(crypto(EceError::InvalidSalt));
+    }
+    if public_key.len() != PUBLIC_KEY_LEN || sender_public_key.len() != PUBLIC_KEY_LEN {
+        return Err(crypto(EceError::InvalidKeyLength));
+    }
+    if buffer.len() > RECORD_SIZE + aead::MAX_TAG_LEN {
+        return Err(crypto(EceError::MultipleRecordsNotSupported));
+    }
+    if buffer.len() < aead::MAX_TAG_LEN + PAD_SIZE {
+        return Err(crypto(EceError::BlockTooShort));
+    }
+
+    // With a single record, the record nonce is the derived one as-is
+    let (key, nonce) = record_key(agreement, public_key, auth_secret, sender_public_key, salt)?;
+    let len = key
+        .open_in_place(nonce, aead::Aad::empty(), buffer)
+        .map_err(|_| crypto(EceError::CryptoError))?
+        .len();
+
+    // The plaintext starts with the padding length, followed by that many zero bytes
+    let padding = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+    let start = PAD_SIZE + padding;
+    if start > len || buffer[PAD_SIZE..start].iter().any(|b| *b != 0) {
+        return Err(crypto(EceError::DecryptPadding));
+    }
+
+    Ok(start..len)
+}
+
+/// derives the content encryption key and nonce from the ECDH secret and the auth secret
+fn record_key(
+    agreement: &dyn KeyAgreement,
+    public_key: &[u8],
+    auth_secret: &[u8],
+    sender_public_key: &[u8],
+    salt: &[u8],
+) -> Result<(aead::LessSafeKey, aead::Nonce), Error> {
+    let shared_secret = agreement
+        .agree(public_key, sender_public_key)
+        .map_err(Error::KeyAgreement)?;
+
+    let mut ikm = Zeroizing::new([0u8; 32]);
+    hkdf(
+        auth_secret,
+        shared_secret.as_ref(),
+        &[b"Content-Encoding: auth\0"],
+        ikm.as_mut(),
+    );
+
+    // Both public keys go into the context, each prefixed with its length
+    let key_len = (PUBLIC_KEY_LEN as u16).to_be_bytes();
+    let context: [&[u8]; 5] = [
+        b"P-256\0",
+        &key_len,
+        public_key,
+        &key_len,
+        sender_public_key,
+    ];
+    let info = |encoding: &'static [u8]| {
+        let mut info: [&[u8]; 6] = [encoding; 6];
+        info[1..].copy_from_slice(&context);
+        info
+    };
+
+    let mut key = Zeroizing::new([0u8; 16]);
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    hkdf(
+        salt,
+        ikm.as_ref(),
+        &info(b"Content-Encoding: aesgcm\0"),
+        key.as_mut(),
+    );
+    hkdf(
+        salt,
+        ikm.as_ref(),
+        &info(b"Content-Encoding: nonce\0"),
+        &mut nonce,
+    );
+
+    let key = aead::UnboundKey::new(&aead::AES_128_GCM, key.as_ref())
+        .expect("key length should match the algorithm");
+    Ok((
+        aead::LessSafeKey::new(key),
+        aead::Nonce::assume_unique_for_key(nonce),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WebPushKeys;
+
+    // The example in section 5 of draft-ietf-webpush-encryption-04
+    const PRIVATE_KEY: &str = "f455a5d79fd05100160da0f7937979d19059409e1abb6ec5d55e05d2e2d20ff3";
+    const PUBLIC_KEY: &str = "042124063ccbf19dc2fa88b643ba04e6dd8da7ea7ba2c8c62e0f77a943f4c2fa914f6d44116c9fd1c40341c6a440cab3e2140a60e4378a5da735972de078005105";
+    const AUTH_SECRET: &str = "476f6f20676f6f206727206a6f6f6221";
+    const SALT: &str = "96781aadbc8a7cca22f59ef9c585e692";
+    const SENDER_PUBLIC_KEY: &str = "04da110db6fce091a6f20e59e42171bab4aab17589d7522d7d71166152c4f3963b0989038d7b0811ce1aab161a4351bc06a917089e833e90eb5ad7568ff9ae8075";
+    const CIPHERTEXT: &str = "ea7a80414304f2136ac39277925f1ca55549ca55ca62a64e7ac7991bc52e78aa40";
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn keys() -> WebPushKeys {
+        WebPushKeys {
+            public_key: hex(PUBLIC_KEY),
+            private_key: hex(PRIVATE_KEY),
+            auth_secret: hex(AUTH_SECRET),
+        }
+    }
+
+    fn open(
+        keys: &WebPushKeys,
+        sender_public_key: &[u8],
+        salt: &[u8],
+        record: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let mut buffer = record.to_vec();
+        let range = decrypt(
+            keys,
+            &keys.public_key,
+            &keys.auth_secret,
+            sender_public_key,
+            salt,
+            &mut buffer,
+        )?;
+        Ok(buffer[range].to_vec())
+    }
+
+    /// encrypts a record the way a sender would, with the padded plaintext given as-is
+    fn seal(keys: &WebPushKeys, padded: &[u8]) -> Vec<u8> {
+        let (key, nonce) = record_key(
+            keys,
+            &keys.public_key,
+            &keys.auth_secret,
+            &hex(SENDER_PUBLIC_KEY),
+            &hex(SALT),
+        )
+        .unwrap();
+
+        let mut record = padded.to_vec();
+        key.seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut record)
+            .unwrap();
+        record
+    }
+
+    fn assert_crypto_error(result: Result<Vec<u8>, Error>, expected: EceError) {
+        match result {
+            Err(Error::Crypto(_, e)) => assert_eq!(e.to_string(), expected.to_string()),
+            other => panic!("expected {expected:?}, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn decrypts_draft_example() {
+        let plaintext = open(
+            &keys(),
+            &hex(SENDER_PUBLIC_KEY),
+            &hex(SALT),
+            &hex(CIPHERTEXT),
+        );
+        assert_eq!(plaintext.unwrap(), b"I am the walrus");
+    }
+
+    #[test]
+    fn decrypts_ece_records() {
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use base64::Engine;
+
+        let (key_pair, auth_secret) = ece::generate_keypair_and_auth_secret().unwrap();
+        let components = key_pair.raw_components().unwrap();
+        let keys = WebPushKeys {
+            public_key: components.public_key().into(),
+            private_key: components.private_key().into(),
+            auth_secret: auth_secret.into(),
+        };
+
+        for plaintext in [&b"x"[..], b"I am the walrus", &[0x5a; 3000]] {
+            let block = ece::legacy::encrypt_aesgcm(&keys.public_key, &keys.auth_secret, plaintext)
+                .unwrap();
+
+            let headers = block.headers(None);
+            let param = |name: &str, prefix: &str| {
+                let (_, value) = headers.iter().find(|(n, _)| *n == name).unwrap();
+                let value = value
+                    .strip_prefix(prefix)
+                    .unwrap()
+                    .split(';')
+                    .next()
+                    .unwrap();
+                URL_SAFE_NO_PAD.decode(value).unwrap()
+            };
+            let sender_public_key = param("Crypto-Key", "dh=");
+            let salt = param("Encryption", "salt=");
+            let record = URL_SAFE_NO_PAD.decode(block.body()).unwrap();
+
+            let decrypted = open(&keys, &sender_public_key, &salt, &record).unwrap();
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn decrypts_empty_record() {
+        let keys = keys();
+        let record = seal(&keys, &[0, 0]);
+        assert_eq!(record.len(), aead::MAX_TAG_LEN + PAD_SIZE);
+
+        let plaintext = open(&keys, &hex(SENDER_PUBLIC_KEY), &hex(SALT), &record);
+        assert_eq!(plaintext.unwrap(), b"");
+    }
+
+    #[test]
+    fn strips_padding() {
+        let keys = keys();
+        let record = seal(&keys, b"\x00\x03\x00\x00\x00walrus");
+
+        let plaintext = open(&keys, &hex(SENDER_PUBLIC_KEY), &hex(SALT), &record);
+        assert_eq!(plaintext.unwrap(), b"walrus");
+    }
+
+    #[test]
+    fn rejects_wrong_auth_secret() {
+        let mut keys = keys();
+        keys.auth_secret[0] ^= 1;
+
+        let result = open(&keys, &hex(SENDER_PUBLIC_KEY), &hex(SALT), &hex(CIPHERTEXT));
+        assert_crypto_error(result, EceError::CryptoError);
+    }
+
+    #[test]
+    fn rejects_truncated_record() {
+        let keys = keys();
+        let record = hex(CIPHERTEXT);
+
+        let result = open(
+            &keys,
+            &hex(SENDER_PUBLIC_KEY),
+            &hex(SALT),
+            &record[..record.len() - 1],
+        );
+        assert_crypto_error(result, EceError::CryptoError);
+
+        let result = open(&keys, &hex(SENDER_PUBLIC_KEY), &hex(SALT), &record[..17]);
+        assert_crypto_error(result, EceError::BlockTooShort);
+    }
+
+    #[test]
+    fn rejects_bad_padding() {
+        let keys = keys();
+
+        // longer than the record
+        let record = seal(&keys, b"\x00\x09walrus");
+        let result = open(&keys, &hex(SENDER_PUBLIC_KEY), &hex(SALT), &record);
+        assert_crypto_error(result, EceError::DecryptPadding);
+
+        // not all zeros
+        let record = seal(&keys, b"\x00\x02\x00\x01walrus");
+        let result = open(&keys, &hex(SENDER_PUBLIC_KEY), &hex(SALT), &record);
+        assert_crypto_error(result, EceError::DecryptPadding);
+
+        // no room for the padding length
+        let record = seal(&keys, b"\x00");
+        let result = open(&keys, &hex(SENDER_PUBLIC_KEY), &hex(SALT), &record);
+        assert_crypto_error(result, EceError::BlockTooShort);
+    }
+}
@@ -0,0 +1,39 @@
+use crate::WebPushKeys;
+use zeroize::Zeroizing;
+
+/// Performs the ECDH step of message decryption on behalf of [`crate::MessageStream`], so the
+/// web push private key can be kept outside the process, e.g. in a key service or a secure
+/// enclave.
+///
+/// Calls block the thread decrypting the message. For anything slower than a local computation,
+/// combine this with [`crate::Decryption::Blocking`] so it doesn't hold up the stream.
+pub trait KeyAgreement: Send + Sync {
+    /// the x coordinate of the P-256 point shared between the private key belonging to
+    /// `public_key` and the sender's ephemeral key, both uncompressed SEC1 encoded
+    fn agree(
+        &self,
+        public_key: &[u8],
+        sender_public_key: &[u8],
+    ) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// The default, with the private key held in memory
+impl KeyAgreement for WebPushKeys {
+    fn agree(
+        &self,
+        public_key: &[u8],
+        sender_public_key: &[u8],
+    ) -> Result<Zeroizing<[u8; 32]>, Box<dyn std::error::Error + Send + Sync>> {
+        if public_key != self.public_key {
+            return Err("public key doesn't belong to these keys".into());
+        }
+        if self.private_key.is_empty() {
+            return Err("private key is held elsewhere".into());
+        }
+
+        let secret = p256::SecretKey::from_slice(&self.private_key)?;
+        let sender = p256::PublicKey::from_sec1_bytes(sender_public_key)?;
+        let shared = p256::ecdh::diffie_hellman(secret.to_nonzero_scalar(), sender.as_affine());
+        Ok(Zeroizing::new((*shared.raw_secret_bytes()).into()))
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{Error, HttpTransport};
+use bytes::Bytes;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The transport to call Google's services with, and how to retry failed calls.
+///
+/// Functions taking one also accept a plain reference to an [`HttpTransport`] such as
+/// `&reqwest::Client`, which uses the default [`RetryPolicy`].
+#[derive(Clone)]
+pub struct Http<'a> {
+    transport: &'a dyn HttpTransport,
+    retry_policy: RetryPolicy,
+}
+
+impl<'a> Http<'a> {
+    pub fn new(transport: &'a impl HttpTransport) -> Self {
+        Self {
+            transport,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// sends the request built by `request`, building it anew for every retry
+    pub(crate) async fn send(
+        &self,
+        api: &'static str,
+        idempotency: Idempotency,
+        request: impl Fn() -> Result<http::Request<Vec<u8>>, http::Error>,
+    ) -> Result<http::Response<Bytes>, Error> {
+        let mut attempt = 1;
+        loop {
+            let request =
+                request().map_err(|e| Error::Request(api, crate::TransportError::new(e)))?;
+            let result = match self.transport.send(request).await {
+                Ok(response) => {
+                    let retry_after = retry_after(&response);
+                    check(api, response).map_err(|e| (e, retry_after))
+                }
+                Err(e) => Err((Error::Request(api, e), None)),
+            };
+
+            match result {
+                Ok(response) => return Ok(response),
+                Err((e, retry_after))
+                    if attempt < self.retry_policy.max_attempts
+                        && is_retryable(&e, idempotency) =>
+                {
+                    // Waiting longer than the policy allows would stall the caller, give up
+                    // instead
+                    if retry_after.is_some_and(|delay| delay > self.retry_policy.max_backoff) {
+                        log::debug!("{api} asked to retry after {retry_after:?}, giving up");
+                        return Err(e);
+                    }
+
+                    let delay = retry_after.unwrap_or_else(|| self.retry_policy.backoff(attempt));
+                    log::debug!("{api} attempt {attempt} failed, retrying in {delay:?}: {e}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err((e, _)) => return Err(e),
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Http<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Http")
+            .field("retry_policy", &self.retry_policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, T: HttpTransport> From<&'a T> for Http<'a> {
+    fn from(transport: &'a T) -> Self {
+        Self::new(transport)
+    }
+}
+
+impl<'a> From<&Http<'a>> for Http<'a> {
+    fn from(http: &Http<'a>) -> Self {
+        http.clone()
+    }
+}
+
+/// Whether a call has the same effect when the server gets it twice
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Idempotency {
+    Idempotent,
+    /// Creates something, so it is only retried when the server provably didn't act on it
+    NonIdempotent,
+}
+
+/// How calls to Google's services are retried when they time out, fail to connect, or respond
+/// with 408, 429, 500, 502, 503 or 504.
+///
+/// Calls that create something, like a registration, are only retried when the request never
+/// reached the server, or the server turned it away with 408, 429 or 503.
+///
+/// Retries back off exponentially with full jitter, unless the response says how long to wait
+/// in its `Retry-After` header. A call asking for a longer wait than the maximum backoff isn't
+/// retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// give up after the first failure
+    pub fn none() -> Self {
+        Self::default().with_max_attempts(1)
+    }
+
+    /// how often a call is tried in total, 3 by default
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// the longest wait before the first retry, doubling for each retry after that. 1 second by
+    /// default.
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// caps the backoff, 30 seconds by default. Responses asking to wait longer than this
+    /// aren't retried.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// a random delay of up to the exponential backoff for the given attempt
+    fn backoff(&self, attempt: u32) -> Duration {
+        use rand::Rng;
+
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(1 << (attempt - 1).min(31))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::rng().random::<f64>())
+    }
+}
+
+/// the delay the server asked for, if it gave one in seconds
+fn retry_after(response: &http::Response<Bytes>) -> Option<Duration> {
+    let value = response.headers().get(http::header::RETRY_AFTER)?;
+    let seconds = value.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+fn is_retryable(error: &Error, idempotency: Idempotency) -> bool {
+    use http::StatusCode;
+
+    match (error, idempotency) {
+        (Error::Request(_, e), Idempotency::Idempotent) => e.is_retryable(),
+        (Error::Request(_, e), Idempotency::NonIdempotent) => e.is_not_sent(),
+        (Error::Api(e), Idempotency::Idempotent) => e.is_retryable(),
+        (Error::Api(e), Idempotency::NonIdempotent) => matches!(
+            e.http_status,
+            StatusCode::REQUEST_TIMEOUT
+                | StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::SERVICE_UNAVAILABLE
+        ),
+        _ => false,
+    }
+}
+
+/// A Google API responded with an error status
+#[derive(Debug)]
+pub struct ApiError {
+    /// Which call failed
+    pub api: &'static str,
+    pub http_status: http::StatusCode,
+
+    /// The numeric `error.code` of the response, usually the HTTP status again
+    pub code: Option<i32>,
+
+    /// The `error.message` of the response, or the response text if it wasn't the usual JSON
+    pub message: Option<String>,
+
+    /// The canonical error code, e.g. `PERMISSION_DENIED`. For the GCM registration endpoint,
+    /// which reports errors with a 200, the reason it gave, e.g. `PHONE_REGISTRATION_ERROR`.
+    pub status: Option<String>,
+
+    /// Machine readable details, e.g. the reason a request was denied
+    pub details: Vec<ErrorDetail>,
+}
+
+impl ApiError {
+    /// whether the API refused the request because of something on our end, such as a wrong or
+    /// insufficiently privileged API key, rather than failing to handle it
+    pub fn is_rejection(&self) -> bool {
+        use http::StatusCode;
+
+        // An error in a successful response is a refusal, like GCM's `Error=` bodies
+        self.http_status.is_success()
+            || self.http_status.is_client_error()
+                && !matches!(
+                    self.http_status,
+                    StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+                )
+    }
+
+    /// whether the call might succeed when tried again later
+    pub fn is_retryable(&self) -> bool {
+        use http::StatusCode;
+
+        matches!(
+            self.http_status,
+            StatusCode::REQUEST_TIMEOUT
+                | StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::INTERNAL_SERVER_ERROR
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        )
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let blame = match self.is_rejection() {
+            true => "rejected request",
+            false => "failed",
+        };
+
+        write!(f, "{} API {blame} with {}", self.api, self.http_status)?;
+        if let Some(status) = &self.status {
+            write!(f, " {status}")?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {message}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// An entry of `error.details`. Only `google.rpc.ErrorInfo` entries fill in more than the type.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ErrorDetail {
+    /// e.g. `type.googleapis.com/google.rpc.ErrorInfo`
+    #[serde(rename = "@type")]
+    pub type_url: String,
+
+    /// e.g. `API_KEY_SERVICE_BLOCKED`
+    pub reason: Option<String>,
+    pub domain: Option<String>,
+
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    code: Option<i32>,
+    message: Option<String>,
+    status: Option<String>,
+
+    #[serde(default)]
+    details: Vec<ErrorDetail>,
+}
+
+/// passes successful responses through and turns the others into an [`ApiError`]
+fn check(
+    api: &'static str,
+    response: http::Response<Bytes>,
+) -> Result<http::Response<Bytes>, Error> {
+    let http_status = response.status();
+    if http_status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.into_body();
+    let error = match serde_json::from_slice::<ErrorResponse>(&body) {
+        Ok(ErrorResponse { error }) => ApiError {
+            api,
+            http_status,
+            code: error.code,
+            message: error.message,
+            status: error.status,
+            details: error.details,
+        },
+        Err(_) => {
+            let text = String::from_utf8_lossy(&body).trim().to_owned();
+            ApiError {
+                api,
+                http_status,
+                code: None,
+                message: (!text.is_empty()).then_some(text),
+                status: None,
+                details: Vec::new(),
+            }
+        }
+    };
+
+    Err(Error::Api(Box::new(error)))
+}
+
+/// a header value holding a credential, marked so transports leave it out of their logs
+pub(crate) fn sensitive_header(value: &str) -> Result<http::HeaderValue, http::Error> {
+    let mut header = http::HeaderValue::from_str(value)?;
+    header.set_sensitive(true);
+    Ok(header)
+}
@@ -2,11 +2,19 @@ pub use fcm_push_listener::Error;
 use fcm_push_listener::{new_heartbeat_ack, MessageStream, Registration, Session as GcmSession, WebPushKeys};
 use tokio::io::AsyncWriteExt;
 
-async fn run(registration: Registration) -> Result<(), fcm_push_listener::Error> {
+async fn run(mut registration: Registration) -> Result<(), fcm_push_listener::Error> {
     use tokio_stream::StreamExt;
 
+    registration.validate()?;
+
     let http = reqwest::Client::new();
-    let session = registration.gcm.checkin(&http).await?;
+    let session = registration
+        .checkin(&http, |r| {
+            let json = serde_json::to_string(r).map_err(|e| Error::Json("registration", e))?;
+            println!("Registration updated, save it: {json}");
+            Ok(())
+        })
+        .await?;
     let connection = session.new_connection(vec![]).await?;
     let mut stream = MessageStream::wrap(connection, &registration.keys);
 
@@ -40,15 +48,23 @@ async fn main() {
         gcm: GcmSession {
             android_id: 123,
             security_token: 456,
+            device: Default::default(),
+            digest: None,
         },
         keys: WebPushKeys {
             auth_secret: vec![],
             private_key: vec![],
             public_key: vec![],
         },
+        gcm_app_id: None,
+        installation: None,
     };
 
-    tokio::spawn(run(registration));
+    tokio::spawn(async move {
+        if let Err(e) = run(registration).await {
+            println!("Error: {e}");
+        }
+    });
 
     println!("Listening for push messages. Press any key to exit");
     let mut buf = [0u8; 1];
@@ -0,0 +1,383 @@
+use crate::Error;
+use serde::Deserialize;
+
+/// Identifies the Firebase app to register with.
+///
+/// Build one with [`FirebaseConfig::builder`], or load it from the files the Firebase console
+/// hands out: [`FirebaseConfig::from_google_services_json`] for Android apps and
+/// [`FirebaseConfig::from_web_config`] for web apps.
+#[derive(Clone, Debug)]
+pub struct FirebaseConfig {
+    app_id: String,
+    project_id: String,
+    api_key: String,
+    vapid_key: Option<String>,
+}
+
+impl FirebaseConfig {
+    pub fn builder() -> FirebaseConfigBuilder {
+        FirebaseConfigBuilder::default()
+    }
+
+    /// load the config of an Android app from its `google-services.json`
+    ///
+    /// The file lists every app of the project. `package_name` picks the one to use, otherwise
+    /// the file must contain a single app.
+    pub fn from_google_services_json(
+        json: &str,
+        package_name: Option<&str>,
+    ) -> Result<Self, Error> {
+        let services: GoogleServices =
+            serde_json::from_str(json).map_err(|e| Error::Json("google-services.json", e))?;
+
+        let mut clients = services.client.into_iter().filter(|c| {
+            package_name.is_none_or(|name| {
+                c.client_info.android_client_info.package_name.as_deref() == Some(name)
+            })
+        });
+
+        let client = clients
+            .next()
+            .ok_or(Error::InvalidFirebaseConfig("has no matching app"))?;
+        if clients.next().is_some() {
+            return Err(Error::InvalidFirebaseConfig(
+                "has several apps, pick one by package name",
+            ));
+        }
+
+        let api_key = client
+            .api_key
+            .into_iter()
+            .next()
+            .ok_or(Error::InvalidFirebaseConfig("has no API key"))?;
+
+        Self::builder()
+            .with_app_id(client.client_info.mobilesdk_app_id)
+            .with_project_id(services.project_info.project_id)
+            .with_api_key(api_key.current_key)
+            .build()
+    }
+
+    /// load the config of a web app from the `firebaseConfig` object in the console's setup
+    /// snippet, written as JSON
+    pub fn from_web_config(json: &str) -> Result<Self, Error> {
+        let config: WebConfig =
+            serde_json::from_str(json).map_err(|e| Error::Json("firebaseConfig", e))?;
+
+        Self::builder()
+            .with_app_id(config.app_id)
+            .with_project_id(config.project_id)
+            .with_api_key(config.api_key)
+            .build()
+    }
+
+    /// public key of the application server, if it signs its messages (VAPID)
+    pub fn with_vapid_key(mut self, vapid_key: impl Into<String>) -> Self {
+        self.vapid_key = Some(vapid_key.into());
+        self
+    }
+
+    /// e.g. `1:1001234567890:android:2665128ba997ffab830a24`
+    pub fn app_id(&self) -> &str {
+        &self.app_id
+    }
+
+    /// e.g. `myapp-1234567890123`
+    pub fn project_id(&self) -> &str {
+        &self.project_id
+    }
+
+    pub fn api_key(&self) -> &str {
+        &self.api_key
+    }
+
+    pub fn vapid_key(&self) -> Option<&str> {
+        self.vapid_key.as_deref()
+    }
+}
+
+/// Collects the parts of a [`FirebaseConfig`], checked when building it
+#[derive(Clone, Debug, Default)]
+pub struct FirebaseConfigBuilder {
+    app_id: Option<String>,
+    project_id: Option<String>,
+    api_key: Option<String>,
+    vapid_key: Option<String>,
+}
+
+impl FirebaseConfigBuilder {
+    /// Firebase console -> Project settings -> General -> Your apps -> App ID
+    pub fn with_app_id(mut self, app_id: impl Into<String>) -> Self {
+        self.app_id = Some(app_id.into());
+        self
+    }
+
+    /// Firebase console -> Project settings -> General -> Project ID
+    pub fn with_project_id(mut self, project_id: impl Into<String>) -> Self {
+        self.project_id = Some(project_id.into());
+        self
+    }
+
+    /// Google Cloud console -> APIs and Services -> Credentials -> API Keys
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// public key of the application server, if it signs its messages (VAPID)
+    pub fn with_vapid_key(mut self, vapid_key: impl Into<String>) -> Self {
+        self.vapid_key = Some(vapid_key.into());
+        self
+    }
+
+    pub fn build(self) -> Result<FirebaseConfig, Error> {
+        let app_id = self
+            .app_id
+            .ok_or(Error::InvalidFirebaseConfig("is missing the app ID"))?;
+        let project_id = self
+            .project_id
+            .ok_or(Error::InvalidFirebaseConfig("is missing the project ID"))?;
+        let api_key = self
+            .api_key
+            .ok_or(Error::InvalidFirebaseConfig("is missing the API key"))?;
+
+        if !is_app_id(&app_id) {
+            return Err(Error::InvalidFirebaseConfig(
+                "has an app ID not of the form 1:<project number>:<platform>:<hex>",
+            ));
+        }
+        if !is_project_id(&project_id) {
+            return Err(Error::InvalidFirebaseConfig(
+                "has a project ID that isn't 6 to 30 lowercase letters, digits or hyphens",
+            ));
+        }
+        if api_key.is_empty() || api_key.contains(char::is_whitespace) {
+            return Err(Error::InvalidFirebaseConfig("has a malformed API key"));
+        }
+
+        Ok(FirebaseConfig {
+            app_id,
+            project_id,
+            api_key,
+            vapid_key: self.vapid_key,
+        })
+    }
+}
+
+/// `1:<project number>:<platform>:<hex>`, the format of Google app IDs
+fn is_app_id(app_id: &str) -> bool {
+    let parts: Vec<&str> = app_id.split(':').collect();
+    let [version, project_number, platform, hash] = parts[..] else {
+        return false;
+    };
+
+    version == "1"
+        && !project_number.is_empty()
+        && project_number.bytes().all(|b| b.is_ascii_digit())
+        && ["android", "ios", "web"].contains(&platform)
+        && !hash.is_empty()
+        && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Google Cloud project IDs start with a letter, don't end with a hyphen, and are 6 to 30
+/// characters long
+fn is_project_id(project_id: &str) -> bool {
+    let bytes = project_id.as_bytes();
+    (6..=30).contains(&bytes.len())
+        && bytes[0].is_ascii_lowercase()
+        && bytes[bytes.len() - 1] != b'-'
+        && bytes
+            .iter()
+            .all(|&b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+/// The parts of `google-services.json` we need
+#[derive(Deserialize)]
+struct GoogleServices {
+    project_info: ProjectInfo,
+    client: Vec<Client>,
+}
+
+#[derive(Deserialize)]
+struct ProjectInfo {
+    project_id: String,
+}
+
+#[derive(Deserialize)]
+struct Client {
+    client_info: ClientInfo,
+    #[serde(default)]
+    api_key: Vec<ApiKey>,
+}
+
+#[derive(Deserialize)]
+struct ClientInfo {
+    mobilesdk_app_id: String,
+    android_client_info: AndroidClientInfo,
+}
+
+#[derive(Deserialize)]
+struct AndroidClientInfo {
+    package_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiKey {
+    current_key: String,
+}
+
+/// The parts of the web `firebaseConfig` object we need
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebConfig {
+    api_key: String,
+    project_id: String,
+    app_id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOGLE_SERVICES: &str = r#"{
+      "project_info": {
+        "project_number": "1001234567890",
+        "project_id": "myapp-1234567890123",
+        "storage_bucket": "myapp-1234567890123.appspot.com"
+      },
+      "client": [
+        {
+          "client_info": {
+            "mobilesdk_app_id": "1:1001234567890:android:2665128ba997ffab830a24",
+            "android_client_info": { "package_name": "com.example.app" }
+          },
+          "oauth_client": [],
+          "api_key": [{ "current_key": "AIzaSyB-app-key" }]
+        },
+        {
+          "client_info": {
+            "mobilesdk_app_id": "1:1001234567890:android:9f3b1e2d7c4a5b6c830a24",
+            "android_client_info": { "package_name": "com.example.app.debug" }
+          },
+          "api_key": [{ "current_key": "AIzaSyB-debug-key" }]
+        }
+      ],
+      "configuration_version": "1"
+    }"#;
+
+    fn assert_invalid(result: Result<FirebaseConfig, Error>, expected: &str) {
+        match result {
+            Err(Error::InvalidFirebaseConfig(problem)) => assert_eq!(problem, expected),
+            other => panic!("expected {expected:?}, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn picks_google_services_app_by_package() {
+        let config =
+            FirebaseConfig::from_google_services_json(GOOGLE_SERVICES, Some("com.example.app"))
+                .unwrap();
+        assert_eq!(
+            config.app_id(),
+            "1:1001234567890:android:2665128ba997ffab830a24"
+        );
+        assert_eq!(config.project_id(), "myapp-1234567890123");
+        assert_eq!(config.api_key(), "AIzaSyB-app-key");
+
+        let config = FirebaseConfig::from_google_services_json(
+            GOOGLE_SERVICES,
+            Some("com.example.app.debug"),
+        )
+        .unwrap();
+        assert_eq!(config.api_key(), "AIzaSyB-debug-key");
+
+        assert_invalid(
+            FirebaseConfig::from_google_services_json(GOOGLE_SERVICES, None),
+            "has several apps, pick one by package name",
+        );
+    }
+
+    #[test]
+    fn rejects_missing_package() {
+        assert_invalid(
+            FirebaseConfig::from_google_services_json(GOOGLE_SERVICES, Some("com.example.other")),
+            "has no matching app",
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_ids() {
+        let builder = FirebaseConfig::builder()
+            .with_app_id("1:1001234567890:web:2665128ba997ffab830a24")
+            .with_project_id("myapp-1234567890123")
+            .with_api_key("AIzaSyB-app-key");
+        builder.clone().build().unwrap();
+
+        for app_id in [
+            "2:1001234567890:web:2665128ba997ffab830a24",
+            "1:myapp:web:2665128ba997ffab830a24",
+            "1:1001234567890:desktop:2665128ba997ffab830a24",
+            "1:1001234567890:web:not-hex",
+            "1:1001234567890:web:",
+            "1001234567890:web:2665128ba997ffab830a24",
+        ] {
+            assert_invalid(
+                builder.clone().with_app_id(app_id).build(),
+                "has an app ID not of the form 1:<project number>:<platform>:<hex>",
+            );
+        }
+
+        for project_id in [
+            "myapp",
+            "1myapp-1234567890123",
+            "myapp-1234567890123-",
+            "MyApp-1234567890123",
+            "myapp_1234567890123",
+            "myapp-1234567890123-12345678901",
+        ] {
+            assert_invalid(
+                builder.clone().with_project_id(project_id).build(),
+                "has a project ID that isn't 6 to 30 lowercase letters, digits or hyphens",
+            );
+        }
+
+        assert_invalid(
+            builder.clone().with_api_key("AIza SyB").build(),
+            "has a malformed API key",
+        );
+        assert_invalid(
+            FirebaseConfig::builder()
+                .with_app_id("1:1001234567890:web:2665128ba997ffab830a24")
+                .with_api_key("AIzaSyB-app-key")
+                .build(),
+            "is missing the project ID",
+        );
+    }
+
+    #[test]
+    fn loads_web_config() {
+        let config = FirebaseConfig::from_web_config(
+            r#"{
+              "apiKey": "AIzaSyB-web-key",
+              "authDomain": "myapp-1234567890123.firebaseapp.com",
+              "projectId": "myapp-1234567890123",
+              "storageBucket": "myapp-1234567890123.appspot.com",
+              "messagingSenderId": "1001234567890",
+              "appId": "1:1001234567890:web:2665128ba997ffab830a24"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.api_key(), "AIzaSyB-web-key");
+        assert_eq!(config.project_id(), "myapp-1234567890123");
+
+        let result = FirebaseConfig::from_web_config(
+            r#"{"apiKey": "AIzaSyB-web-key", "projectId": "myapp-1234567890123"}"#,
+        );
+        match result {
+            Err(Error::Json("firebaseConfig", e)) => {
+                assert!(e.to_string().contains("missing field `appId`"), "{e}")
+            }
+            other => panic!("expected a missing field, got {other:?}"),
+        }
+    }
+}
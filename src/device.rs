@@ -0,0 +1,252 @@
+use crate::gcm::contract;
+use serde::{Deserialize, Serialize};
+
+/// The Chrome install we present ourselves as to the checkin and the push connection.
+///
+/// Defaults to the Chrome 63 on macOS this crate has always used. It is stored with the
+/// [`Session`](crate::Session), so the checkin and the login of later connections keep
+/// presenting the same identity.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceProfile {
+    platform: Platform,
+    channel: Channel,
+    chrome_version: String,
+
+    /// e.g. `en_US`
+    #[serde(default)]
+    locale: Option<String>,
+
+    /// e.g. `America/New_York`
+    #[serde(default)]
+    time_zone: Option<String>,
+
+    device_type: DeviceType,
+
+    #[serde(default)]
+    network_type: NetworkType,
+
+    /// The app the GCM token is requested for
+    #[serde(default = "default_app")]
+    app: String,
+}
+
+fn default_app() -> String {
+    "org.chromium.linux".into()
+}
+
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        Self {
+            platform: Platform::Mac,
+            channel: Channel::Stable,
+            chrome_version: "63.0.3234.0".into(),
+            locale: None,
+            time_zone: None,
+            device_type: DeviceType::ChromeBrowser,
+            network_type: NetworkType::default(),
+            app: default_app(),
+        }
+    }
+}
+
+impl DeviceProfile {
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    pub fn with_channel(mut self, channel: Channel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// the full version, e.g. `131.0.6778.85`
+    pub fn with_chrome_version(mut self, chrome_version: impl Into<String>) -> Self {
+        self.chrome_version = chrome_version.into();
+        self
+    }
+
+    /// the locale in `xx_XX` form, left out of the checkin by default
+    pub fn with_locale(mut self, locale: impl Into<String>) -> Self {
+        self.locale = Some(locale.into());
+        self
+    }
+
+    /// the IANA time zone, left out of the checkin by default
+    pub fn with_time_zone(mut self, time_zone: impl Into<String>) -> Self {
+        self.time_zone = Some(time_zone.into());
+        self
+    }
+
+    pub fn with_device_type(mut self, device_type: DeviceType) -> Self {
+        self.device_type = device_type;
+        self
+    }
+
+    /// the connection reported when logging in to the push connection, ethernet by default
+    pub fn with_network_type(mut self, network_type: NetworkType) -> Self {
+        self.network_type = network_type;
+        self
+    }
+
+    /// the app the GCM token is requested for, which incoming messages carry as their
+    /// [`category`](crate::DataMessage::category). `org.chromium.linux` by default, whatever
+    /// the platform.
+    pub fn with_app(mut self, app: impl Into<String>) -> Self {
+        self.app = app.into();
+        self
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn chrome_version(&self) -> &str {
+        &self.chrome_version
+    }
+
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    pub fn time_zone(&self) -> Option<&str> {
+        self.time_zone.as_deref()
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    pub fn network_type(&self) -> NetworkType {
+        self.network_type
+    }
+
+    pub fn app(&self) -> &str {
+        &self.app
+    }
+
+    pub(crate) fn checkin_proto(&self) -> contract::AndroidCheckinProto {
+        contract::AndroidCheckinProto {
+            r#type: Some(self.device_type.proto() as i32),
+            chrome_build: Some(contract::ChromeBuildProto {
+                platform: Some(self.platform.proto() as i32),
+                channel: Some(self.channel.proto() as i32),
+                chrome_version: Some(self.chrome_version.clone()),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// the client id of the push connection login
+    pub(crate) fn login_id(&self) -> String {
+        format!("chrome-{}", self.chrome_version)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Platform {
+    Windows,
+    Mac,
+    Linux,
+    ChromeOs,
+    Ios,
+    Android,
+}
+
+impl Platform {
+    fn proto(self) -> contract::chrome_build_proto::Platform {
+        use contract::chrome_build_proto::Platform as Proto;
+
+        match self {
+            Self::Windows => Proto::Win,
+            Self::Mac => Proto::Mac,
+            Self::Linux => Proto::Linux,
+            Self::ChromeOs => Proto::Cros,
+            Self::Ios => Proto::Ios,
+            Self::Android => Proto::Android,
+        }
+    }
+}
+
+/// The release channel of the Chrome install
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Stable,
+    Beta,
+    Dev,
+    Canary,
+    /// Tip of tree or custom builds
+    Unknown,
+}
+
+impl Channel {
+    fn proto(self) -> contract::chrome_build_proto::Channel {
+        use contract::chrome_build_proto::Channel as Proto;
+
+        match self {
+            Self::Stable => Proto::Stable,
+            Self::Beta => Proto::Beta,
+            Self::Dev => Proto::Dev,
+            Self::Canary => Proto::Canary,
+            Self::Unknown => Proto::Unknown,
+        }
+    }
+}
+
+/// The connection the device is on, as reported to the push connection
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkType {
+    Unknown,
+    #[default]
+    Ethernet,
+    Wifi,
+    Cellular2g,
+    Cellular3g,
+    Cellular4g,
+    Cellular5g,
+    Bluetooth,
+}
+
+impl NetworkType {
+    /// the value of Chrome's `net::NetworkChangeNotifier::ConnectionType`
+    pub(crate) fn proto(self) -> i32 {
+        match self {
+            Self::Unknown => 0,
+            Self::Ethernet => 1,
+            Self::Wifi => 2,
+            Self::Cellular2g => 3,
+            Self::Cellular3g => 4,
+            Self::Cellular4g => 5,
+            Self::Bluetooth => 7,
+            Self::Cellular5g => 8,
+        }
+    }
+}
+
+/// What kind of device checks in
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType {
+    AndroidOs,
+    IosOs,
+    ChromeBrowser,
+    ChromeOs,
+}
+
+impl DeviceType {
+    fn proto(self) -> contract::DeviceType {
+        match self {
+            Self::AndroidOs => contract::DeviceType::DeviceAndroidOs,
+            Self::IosOs => contract::DeviceType::DeviceIosOs,
+            Self::ChromeBrowser => contract::DeviceType::DeviceChromeBrowser,
+            Self::ChromeOs => contract::DeviceType::DeviceChromeOs,
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{DataMessage, Error};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+/// The JSON object FCM wraps around every pushed payload
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope<T = serde_json::Value> {
+    /// The `data` object the sender pushed
+    pub data: Option<T>,
+
+    /// Sender ID, i.e. the Firebase project number
+    pub from: Option<String>,
+
+    /// `normal` or `high`
+    pub priority: Option<String>,
+
+    pub fcm_message_id: Option<String>,
+
+    /// Present for notification messages, e.g. Firebase console campaigns
+    pub notification: Option<Notification>,
+
+    pub fcm_options: Option<FcmOptions>,
+}
+
+impl<T> Envelope<T> {
+    /// where to take the user when the notification is clicked
+    pub fn link(&self) -> Option<&str> {
+        self.fcm_options
+            .as_ref()
+            .and_then(|o| o.link.as_deref())
+            .or_else(|| self.notification.as_ref()?.click_action.as_deref())
+    }
+}
+
+/// What the sender asked to be displayed to the user
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Notification {
+    pub title: Option<String>,
+    pub body: Option<String>,
+
+    /// URL of an image to show in the notification
+    pub image: Option<String>,
+
+    /// URL of the notification icon
+    pub icon: Option<String>,
+
+    /// Legacy click target, superseded by [`FcmOptions::link`]
+    pub click_action: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FcmOptions {
+    /// Where to take the user when the notification is clicked
+    pub link: Option<String>,
+    pub analytics_label: Option<String>,
+}
+
+/// Prefixes of the keys FCM reserves in the app data of unencrypted messages
+const RESERVED_PREFIXES: [&str; 2] = ["gcm.", "google."];
+
+/// Prefixes of the notification keys in the app data of unencrypted messages, long form first
+const NOTIFICATION_PREFIXES: [&str; 2] = ["gcm.notification.", "gcm.n."];
+
+impl DataMessage {
+    /// parse the body as an FCM envelope, deserializing its `data` object into `T`
+    ///
+    /// Unencrypted messages carry their data as plain key/value pairs rather than a JSON body,
+    /// in which case the envelope is built from [`DataMessage::app_data`].
+    pub fn envelope<T: DeserializeOwned>(&self) -> Result<Envelope<T>, Error> {
+        const KIND: &str = "FCM message envelope";
+
+        if self.key.is_none() && self.body.is_empty() {
+            return self.app_data_envelope().map_err(|e| Error::Json(KIND, e));
+        }
+
+        let body = std::str::from_utf8(&self.body).map_err(|e| Error::Utf8(KIND, e))?;
+        serde_json::from_str(body).map_err(|e| Error::Json(KIND, e))
+    }
+
+    /// deserialize the `data` object of the envelope into `T`
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        use serde::de::Error as _;
+
+        self.envelope()?.data.ok_or_else(|| {
+            Error::Json(
+                "FCM message envelope",
+                serde_json::Error::missing_field("data"),
+            )
+        })
+    }
+
+    /// the notification to display, if this is a notification message
+    pub fn notification(&self) -> Result<Option<Notification>, Error> {
+        Ok(self.envelope::<serde::de::IgnoredAny>()?.notification)
+    }
+
+    fn app_data_envelope<T: DeserializeOwned>(&self) -> Result<Envelope<T>, serde_json::Error> {
+        let data: serde_json::Map<String, serde_json::Value> = self
+            .app_data
+            .iter()
+            .filter(|(k, _)| !RESERVED_PREFIXES.iter().any(|p| k.starts_with(p)))
+            .map(|(k, v)| (k.clone(), v.clone().into()))
+            .collect();
+
+        let field = |name: &str| {
+            NOTIFICATION_PREFIXES
+                .iter()
+                .find_map(|p| self.app_data.get(&format!("{p}{name}")))
+                .cloned()
+        };
+
+        let notification = Notification {
+            title: field("title"),
+            body: field("body"),
+            image: field("image"),
+            icon: field("icon"),
+            click_action: field("click_action"),
+        };
+
+        let has_notification = notification.title.is_some() || notification.body.is_some();
+        let link = field("link");
+        Ok(Envelope {
+            data: Some(T::deserialize(serde_json::Value::Object(data))?),
+            from: Some(self.from.clone()),
+            priority: None,
+            fcm_message_id: None,
+            notification: has_notification.then_some(notification),
+            fcm_options: link.map(|link| FcmOptions {
+                link: Some(link),
+                analytics_label: None,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KeyId;
+    use std::collections::HashMap;
+
+    fn message(body: &str) -> DataMessage {
+        DataMessage {
+            body: bytes::Bytes::copy_from_slice(body.as_bytes()),
+            persistent_id: Some("0:1700000000000000%7031b2e6f9fd7ecd".to_owned()),
+            app_data: HashMap::new(),
+            key: Some(KeyId::Current),
+            from: "1001234567890".to_owned(),
+            category: "org.chromium.linux".to_owned(),
+            to: None,
+            collapse_key: None,
+            ttl: None,
+            sent: None,
+            queued: None,
+            immediate_ack: false,
+            from_trusted_server: false,
+            expired: false,
+        }
+    }
+
+    #[test]
+    fn parses_web_push_envelope() {
+        // as decrypted from a message sent with the HTTP v1 API's `webpush` options
+        let message = message(
+            r#"{"data":{"score":"850","time":"2:45"},"from":"1001234567890","priority":"high","notification":{"title":"Portugal vs. Denmark","body":"great match!","image":"https://example.com/match.png"},"fcmOptions":{"link":"https://example.com/match","analyticsLabel":"match_day"},"fcmMessageId":"5f1e4b8c-8b6a-4b8e-9d6e-1c2f3a4b5c6d"}"#,
+        );
+
+        let envelope = message.envelope::<HashMap<String, String>>().unwrap();
+        assert_eq!(envelope.data.as_ref().unwrap()["score"], "850");
+        assert_eq!(envelope.from.as_deref(), Some("1001234567890"));
+        assert_eq!(envelope.priority.as_deref(), Some("high"));
+        assert_eq!(
+            envelope.fcm_message_id.as_deref(),
+            Some("5f1e4b8c-8b6a-4b8e-9d6e-1c2f3a4b5c6d")
+        );
+
+        let notification = envelope.notification.as_ref().unwrap();
+        assert_eq!(notification.title.as_deref(), Some("Portugal vs. Denmark"));
+        assert_eq!(
+            notification.image.as_deref(),
+            Some("https://example.com/match.png")
+        );
+
+        let options = envelope.fcm_options.as_ref().unwrap();
+        assert_eq!(options.analytics_label.as_deref(), Some("match_day"));
+        assert_eq!(envelope.link(), Some("https://example.com/match"));
+    }
+
+    #[test]
+    fn builds_envelope_from_app_data() {
+        let mut message = message("");
+        message.key = None;
+        message.app_data = HashMap::from([
+            ("score".to_owned(), "850".to_owned()),
+            ("gcm.n.title".to_owned(), "Portugal vs. Denmark".to_owned()),
+            (
+                "gcm.n.link".to_owned(),
+                "https://example.com/match".to_owned(),
+            ),
+            ("google.c.a.e".to_owned(), "1".to_owned()),
+        ]);
+
+        let envelope = message.envelope::<HashMap<String, String>>().unwrap();
+        assert_eq!(
+            envelope.data.as_ref().unwrap(),
+            &HashMap::from([("score".to_owned(), "850".to_owned())])
+        );
+        assert_eq!(
+            envelope.notification.as_ref().unwrap().title.as_deref(),
+            Some("Portugal vs. Denmark")
+        );
+        assert_eq!(envelope.link(), Some("https://example.com/match"));
+    }
+}
@@ -1,23 +1,48 @@
 use std::error;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// Dependency failed, i.e. we blame them
     DependencyFailure(&'static str, &'static str),
     /// Dependency rejection, i.e. they blame us
+    #[deprecated(note = "rejections are reported as `Error::Api`, see `ApiError::is_rejection`")]
     DependencyRejection(&'static str, String),
+    /// A Google API responded with an error status, see [`crate::ApiError::is_rejection`] for
+    /// who is to blame
+    Api(Box<crate::ApiError>),
     /// Received an encrypted message with no decryption params
     MissingCryptoMetadata(&'static str),
     /// Protobuf deserialization failure, probably a contract change
     ProtobufDecode(&'static str, prost::DecodeError),
+    /// Received a data message that failed to decode, carrying what could be salvaged
+    Undecodable(Box<crate::UndecodableMessage>),
+    /// Received an encrypted message with no payload
     EmptyPayload,
-    Request(&'static str, reqwest::Error),
-    Response(&'static str, reqwest::Error),
+    /// The request could not be sent or its response could not be read
+    Request(&'static str, crate::TransportError),
+    /// The response doesn't match the expected JSON schema
+    Response(&'static str, serde_json::Error),
     Base64Decode(&'static str, base64::DecodeError),
     Crypto(&'static str, ece::Error),
+    /// The key agreement for decrypting a message failed
+    KeyAgreement(Box<dyn std::error::Error + Send + Sync>),
+    /// Decrypted payload is not text
+    Utf8(&'static str, std::str::Utf8Error),
+    /// Payload or config file doesn't match the expected JSON schema
+    Json(&'static str, serde_json::Error),
     Socket(std::io::Error),
+    /// Sealed registration state could not be opened
+    Envelope(&'static str),
+    /// Stored registration is corrupt or incomplete
+    InvalidRegistration(&'static str),
+    /// A [`crate::RegistrationBuilder`] step was taken before this earlier one
+    RegistrationIncomplete(crate::RegistrationStage),
+    /// Firebase config is incomplete or malformed
+    InvalidFirebaseConfig(&'static str),
 }
 
+#[allow(deprecated)]
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -25,31 +50,55 @@ impl std::fmt::Display for Error {
             Self::DependencyRejection(api, reason) => {
                 write!(f, "{api} API rejected request: {reason}")
             }
+            Self::Api(e) => e.fmt(f),
             Self::MissingCryptoMetadata(kind) => write!(f, "Missing {kind} metadata on message"),
             Self::ProtobufDecode(kind, e) => write!(f, "Error decoding {kind}: {e}"),
-            Self::EmptyPayload => write!(f, "Received a data message with no payload"),
+            Self::Undecodable(m) => match &m.persistent_id {
+                Some(id) => write!(f, "Error decoding message {id}: {}", m.error),
+                None => write!(f, "Error decoding message: {}", m.error),
+            },
+            Self::EmptyPayload => write!(f, "Received an encrypted data message with no payload"),
             Self::Base64Decode(kind, e) => write!(f, "Error decoding {kind}: {e}"),
             Self::Request(kind, e) => write!(f, "{kind} API request error: {e}"),
             Self::Response(kind, e) => write!(f, "{kind} API response error: {e}"),
             Self::Crypto(kind, e) => write!(f, "Crypto {kind} error: {e}"),
+            Self::KeyAgreement(e) => write!(f, "Key agreement failed: {e}"),
+            Self::Utf8(kind, e) => write!(f, "{kind} is not valid UTF-8: {e}"),
+            Self::Json(kind, e) => write!(f, "Error parsing {kind}: {e}"),
             Self::Socket(e) => write!(f, "TCP error: {e}"),
+            Self::Envelope(problem) => write!(f, "Sealed state {problem}"),
+            Self::InvalidRegistration(problem) => write!(f, "Invalid registration: {problem}"),
+            Self::RegistrationIncomplete(stage) => {
+                write!(f, "Registration step {stage:?} has not been done yet")
+            }
+            Self::InvalidFirebaseConfig(problem) => write!(f, "Firebase config {problem}"),
         }
     }
 }
 
+#[allow(deprecated)]
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
             Self::DependencyFailure(_, _) => None,
             Self::DependencyRejection(_, _) => None,
+            Self::Api(ref e) => Some(e.as_ref()),
             Self::MissingCryptoMetadata(_) => None,
             Self::ProtobufDecode(_, ref e) => Some(e),
+            Self::Undecodable(ref m) => Some(&m.error),
             Self::EmptyPayload => None,
             Self::Base64Decode(_, ref e) => Some(e),
             Self::Request(_, ref e) => Some(e),
             Self::Response(_, ref e) => Some(e),
             Self::Crypto(_, ref e) => Some(e),
+            Self::KeyAgreement(ref e) => Some(e.as_ref()),
+            Self::Utf8(_, ref e) => Some(e),
+            Self::Json(_, ref e) => Some(e),
             Self::Socket(ref e) => Some(e),
+            Self::Envelope(_) => None,
+            Self::InvalidRegistration(_) => None,
+            Self::RegistrationIncomplete(_) => None,
+            Self::InvalidFirebaseConfig(_) => None,
         }
     }
 }
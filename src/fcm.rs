@@ -1,5 +1,7 @@
-use crate::Error;
+use crate::api::Idempotency;
+use crate::{Error, FirebaseConfig, Http};
 use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 fn to_base64<S: serde::ser::Serializer>(v: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
     use base64::Engine;
@@ -20,6 +22,10 @@ fn from_base64<'de, D: serde::de::Deserializer<'de>>(
     })
 }
 
+const FCM_REGISTRATION_API: &str = "https://fcmregistrations.googleapis.com/v1";
+const API_KEY_HEADER: &str = "x-goog-api-key";
+const AUTH_HEADER: &str = "x-goog-firebase-installations-auth";
+
 pub struct Registration {
     pub fcm_token: String,
     pub keys: WebPushKeys,
@@ -27,21 +33,18 @@ pub struct Registration {
 
 impl Registration {
     pub async fn request(
-        http: &reqwest::Client,
-        project_id: &str,
-        api_key: &str,
-        application_pub_key: Option<&str>,
+        http: &Http<'_>,
+        firebase: &FirebaseConfig,
         firebase_installation_auth_token: &str,
         gcm_token: &str,
+        push_keys: WebPushKeys,
     ) -> Result<Self, Error> {
         const FCM_API: &str = "https://fcm.googleapis.com/fcm";
-        const FCM_REGISTRATION_API: &str = "https://fcmregistrations.googleapis.com/v1";
 
         let endpoint = format!("{FCM_API}/send/{gcm_token}");
-        let push_keys = WebPushKeys::new().map_err(|e| Error::Crypto("key creation", e))?;
         let request = RegisterRequest {
             web: WebRegistrationRequest {
-                application_pub_key,
+                application_pub_key: firebase.vapid_key(),
                 endpoint: &endpoint,
                 auth: &push_keys.auth_secret,
                 p256dh: &push_keys.public_key,
@@ -49,29 +52,55 @@ impl Registration {
         };
 
         const API_NAME: &str = "FCM Registration";
-        const API_KEY_HEADER: &str = "x-goog-api-key";
-        const AUTH_HEADER: &str = "x-goog-firebase-installations-auth";
 
+        let project_id = firebase.project_id();
         let url = format!("{FCM_REGISTRATION_API}/projects/{project_id}/registrations");
+        let body = serde_json::to_vec(&request).expect("registration request should serialize");
         let response = http
-            .post(url)
-            .json(&request)
-            .header(API_KEY_HEADER, api_key)
-            .header(AUTH_HEADER, firebase_installation_auth_token)
-            .send()
-            .await
-            .map_err(|e| Error::Request(API_NAME, e))?;
-
-        let response: RegisterResponse = response
-            .json()
-            .await
-            .map_err(|e| Error::Response(API_NAME, e))?;
+            .send(API_NAME, Idempotency::NonIdempotent, || {
+                http::Request::post(&url)
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .header(API_KEY_HEADER, firebase.api_key())
+                    .header(
+                        AUTH_HEADER,
+                        crate::api::sensitive_header(firebase_installation_auth_token)?,
+                    )
+                    .body(body.clone())
+            })
+            .await?;
+
+        let response: RegisterResponse =
+            serde_json::from_slice(response.body()).map_err(|e| Error::Response(API_NAME, e))?;
 
         Ok(Self {
             fcm_token: response.token,
             keys: push_keys,
         })
     }
+
+    /// delete the registration of `fcm_token`, so it stops receiving messages
+    pub async fn delete(
+        http: &Http<'_>,
+        firebase: &FirebaseConfig,
+        firebase_installation_auth_token: &str,
+        fcm_token: &str,
+    ) -> Result<(), Error> {
+        const API_NAME: &str = "FCM Registration deletion";
+
+        let project_id = firebase.project_id();
+        let url = format!("{FCM_REGISTRATION_API}/projects/{project_id}/registrations/{fcm_token}");
+        http.send(API_NAME, Idempotency::Idempotent, || {
+            http::Request::delete(&url)
+                .header(API_KEY_HEADER, firebase.api_key())
+                .header(
+                    AUTH_HEADER,
+                    crate::api::sensitive_header(firebase_installation_auth_token)?,
+                )
+                .body(Vec::new())
+        })
+        .await?;
+        Ok(())
+    }
 }
 
 #[derive(Serialize)]
@@ -99,7 +128,11 @@ struct RegisterResponse {
     // web: WebRegistrationResponse,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Wiped from memory when dropped. The `Debug` output only includes the public key.
+///
+/// Deserialization fails if the keys don't pass [`WebPushKeys::validate`].
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+#[serde(try_from = "UncheckedWebPushKeys")]
 pub struct WebPushKeys {
     /// Public key with URL safe base64 encoding, no padding
     #[serde(deserialize_with = "from_base64", serialize_with = "to_base64")]
@@ -114,8 +147,84 @@ pub struct WebPushKeys {
     pub auth_secret: Vec<u8>,
 }
 
+/// Wire form of [`WebPushKeys`], before validation
+#[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
+struct UncheckedWebPushKeys {
+    #[serde(deserialize_with = "from_base64")]
+    public_key: Vec<u8>,
+    #[serde(deserialize_with = "from_base64")]
+    private_key: Vec<u8>,
+    #[serde(deserialize_with = "from_base64")]
+    auth_secret: Vec<u8>,
+}
+
+impl TryFrom<UncheckedWebPushKeys> for WebPushKeys {
+    type Error = Error;
+
+    fn try_from(mut value: UncheckedWebPushKeys) -> Result<Self, Self::Error> {
+        let keys = Self {
+            public_key: std::mem::take(&mut value.public_key),
+            private_key: std::mem::take(&mut value.private_key),
+            auth_secret: std::mem::take(&mut value.auth_secret),
+        };
+        keys.validate()?;
+        Ok(keys)
+    }
+}
+
 impl WebPushKeys {
-    fn new() -> Result<Self, ece::Error> {
+    /// Uncompressed P-256 point
+    const PUBLIC_KEY_LEN: usize = 65;
+    const PRIVATE_KEY_LEN: usize = 32;
+    const AUTH_SECRET_LEN: usize = 16;
+
+    /// check that the keys are well formed and belong together
+    ///
+    /// The private key may be empty when a [`crate::KeyAgreement`] holds it instead, in which
+    /// case only the public key and auth secret are checked.
+    pub fn validate(&self) -> Result<(), Error> {
+        use p256::elliptic_curve::sec1::ToEncodedPoint;
+
+        if self.public_key.len() != Self::PUBLIC_KEY_LEN || self.public_key[0] != 0x04 {
+            return Err(Error::InvalidRegistration(
+                "public key is not an uncompressed P-256 point",
+            ));
+        }
+
+        // OpenSSL exports the scalar without leading zeros, so shorter keys are legitimate
+        if self.private_key.len() > Self::PRIVATE_KEY_LEN {
+            return Err(Error::InvalidRegistration(
+                "private key is longer than a P-256 scalar",
+            ));
+        }
+
+        if self.auth_secret.len() != Self::AUTH_SECRET_LEN {
+            return Err(Error::InvalidRegistration("auth secret is not 16 bytes"));
+        }
+
+        if self.private_key.is_empty() {
+            return match p256::PublicKey::from_sec1_bytes(&self.public_key) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(Error::InvalidRegistration(
+                    "public key is not on the P-256 curve",
+                )),
+            };
+        }
+
+        let private_key = p256::SecretKey::from_slice(&self.private_key).or(Err(
+            Error::InvalidRegistration("private key is out of range"),
+        ))?;
+        let public_key = private_key.public_key().to_encoded_point(false);
+        if public_key.as_bytes() != self.public_key.as_slice() {
+            return Err(Error::InvalidRegistration(
+                "private key does not match the public key",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn new() -> Result<Self, ece::Error> {
         let (key_pair, auth_secret) = ece::generate_keypair_and_auth_secret()?;
         let components = key_pair.raw_components()?;
         Ok(WebPushKeys {
@@ -125,3 +234,100 @@ impl WebPushKeys {
         })
     }
 }
+
+impl std::fmt::Debug for WebPushKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use base64::Engine;
+
+        let public_key = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&self.public_key);
+        f.debug_struct("WebPushKeys")
+            .field("public_key", &public_key)
+            .field("private_key", &crate::REDACTED)
+            .field("auth_secret", &crate::REDACTED)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// saves and loads `keys`, which must fail for `problem`
+    fn assert_rejected(keys: &WebPushKeys, problem: &str) {
+        let json = serde_json::to_string(keys).unwrap();
+        let error = serde_json::from_str::<WebPushKeys>(&json).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Invalid registration: {problem}"),
+        );
+    }
+
+    #[test]
+    fn loads_valid_keys() {
+        let keys = WebPushKeys::new().unwrap();
+        let json = serde_json::to_string(&keys).unwrap();
+        let loaded: WebPushKeys = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.public_key, keys.public_key);
+        assert_eq!(loaded.private_key, keys.private_key);
+        assert_eq!(loaded.auth_secret, keys.auth_secret);
+    }
+
+    #[test]
+    fn rejects_short_public_key() {
+        let mut keys = WebPushKeys::new().unwrap();
+        keys.public_key.pop();
+        assert_rejected(&keys, "public key is not an uncompressed P-256 point");
+    }
+
+    #[test]
+    fn rejects_compressed_public_key() {
+        let mut keys = WebPushKeys::new().unwrap();
+        keys.public_key[0] = 0x02;
+        assert_rejected(&keys, "public key is not an uncompressed P-256 point");
+    }
+
+    #[test]
+    fn rejects_long_private_key() {
+        let mut keys = WebPushKeys::new().unwrap();
+        keys.private_key.insert(0, 0);
+        assert_rejected(&keys, "private key is longer than a P-256 scalar");
+    }
+
+    #[test]
+    fn rejects_wrong_auth_secret_length() {
+        let mut keys = WebPushKeys::new().unwrap();
+        keys.auth_secret.push(0);
+        assert_rejected(&keys, "auth secret is not 16 bytes");
+    }
+
+    #[test]
+    fn rejects_out_of_range_private_key() {
+        let mut keys = WebPushKeys::new().unwrap();
+        keys.private_key = vec![0xff; 32];
+        assert_rejected(&keys, "private key is out of range");
+    }
+
+    #[test]
+    fn rejects_mismatched_private_key() {
+        let mut keys = WebPushKeys::new().unwrap();
+        keys.private_key = WebPushKeys::new().unwrap().private_key.clone();
+        assert_rejected(&keys, "private key does not match the public key");
+    }
+
+    #[test]
+    fn public_keys_without_private_key_round_trip() {
+        let mut keys = WebPushKeys::new().unwrap();
+        keys.private_key.clear();
+        keys.validate().unwrap();
+
+        let json = serde_json::to_string(&keys).unwrap();
+        let loaded: WebPushKeys = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.public_key, keys.public_key);
+        assert!(loaded.private_key.is_empty());
+        assert_eq!(loaded.auth_secret, keys.auth_secret);
+
+        let mut off_curve = keys;
+        off_curve.public_key[64] ^= 1;
+        assert_rejected(&off_curve, "public key is not on the P-256 curve");
+    }
+}
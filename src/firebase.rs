@@ -1,7 +1,12 @@
-use crate::Error;
+use crate::api::Idempotency;
+use crate::keyring::unix_seconds;
+use crate::{Error, FirebaseConfig, Http};
 use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 const INSTALL_API: &str = "https://firebaseinstallations.googleapis.com/v1";
+const SDK_VERSION: &str = "w:0.6.4";
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -15,37 +20,116 @@ struct InstallationRequest<'a> {
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct InstallationResponse {
-    auth_token: InstallationAuthToken,
-    // fid: String,
+    auth_token: AuthTokenResponse,
+    fid: String,
     // name: String,
-    // refresh_token: String,
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAuthTokenRequest<'a> {
+    installation: InstallationMetadata<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InstallationMetadata<'a> {
+    app_id: &'a str,
+    sdk_version: &'a str,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
+struct AuthTokenResponse {
+    token: String,
+    /// Seconds with an `s` suffix, e.g. `604800s`
+    expires_in: String,
+}
+
+impl AuthTokenResponse {
+    fn into_token(self, api: &'static str) -> Result<InstallationAuthToken, Error> {
+        let expires_in = self
+            .expires_in
+            .strip_suffix('s')
+            .and_then(|s| s.parse::<f64>().ok())
+            .ok_or(Error::DependencyFailure(
+                api,
+                "responded with malformed expiry",
+            ))?;
+
+        Ok(InstallationAuthToken {
+            value: self.token,
+            expires_at: unix_seconds(SystemTime::now()) + expires_in as u64,
+        })
+    }
+}
+
+/// Authorizes calls to FCM on behalf of an installation. Wiped from memory when dropped.
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct InstallationAuthToken {
-    // expires_in: String,
     #[serde(rename = "token")]
     pub value: String,
+
+    /// Seconds since the unix epoch at which the token expires
+    pub expires_at: u64,
 }
 
 impl InstallationAuthToken {
-    pub async fn request(
-        http: &reqwest::Client,
-        application_id: &str,
-        project_id: &str,
-        api_key: &str,
+    /// whether the token expires within the hour, which is when the Firebase SDKs renew it
+    pub fn is_expiring(&self, now: SystemTime) -> bool {
+        const RENEWAL_MARGIN: u64 = 60 * 60;
+        unix_seconds(now) + RENEWAL_MARGIN >= self.expires_at
+    }
+}
+
+impl std::fmt::Debug for InstallationAuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InstallationAuthToken")
+            .field("value", &crate::REDACTED)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// The Firebase installation (FID) a registration was made with. Keep it with the registration,
+/// so its auth token can be renewed instead of creating a new installation every time.
+///
+/// Wiped from memory when dropped. The `Debug` output leaves out the tokens.
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct Installation {
+    pub fid: String,
+    pub refresh_token: String,
+    pub auth_token: InstallationAuthToken,
+}
+
+impl std::fmt::Debug for Installation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Installation")
+            .field("fid", &self.fid)
+            .field("refresh_token", &crate::REDACTED)
+            .field("auth_token", &self.auth_token)
+            .finish()
+    }
+}
+
+impl Installation {
+    pub async fn create<'a>(
+        http: impl Into<Http<'a>>,
+        firebase: &FirebaseConfig,
     ) -> Result<Self, Error> {
         use base64::engine::general_purpose::URL_SAFE_NO_PAD as Base64;
         use base64::engine::Engine;
 
+        let http = http.into();
+
         let fid = generate_fid();
 
         let request = InstallationRequest {
-            app_id: application_id,
+            app_id: firebase.app_id(),
             auth_version: "FIS_v2",
             fid: &fid,
-            sdk_version: "w:0.6.4",
+            sdk_version: SDK_VERSION,
         };
 
         let heartbeat_json = "{\"heartbeats\": [], \"version\": 2}";
@@ -53,19 +137,107 @@ impl InstallationAuthToken {
 
         const API: &str = "Firebase installation";
 
+        let project_id = firebase.project_id();
+        let url = format!("{INSTALL_API}/projects/{project_id}/installations");
+        let body = serde_json::to_vec(&request).expect("installation request should serialize");
         let response = http
-            .post(format!("{INSTALL_API}/projects/{project_id}/installations"))
-            .json(&request)
-            .header("x-firebase-client", heartbeat_header_value)
-            .header("x-goog-api-key", api_key)
-            .send()
-            .await
-            .map_err(|e| Error::Request(API, e))?;
+            .send(API, Idempotency::NonIdempotent, || {
+                http::Request::post(&url)
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .header("x-firebase-client", &heartbeat_header_value)
+                    .header("x-goog-api-key", firebase.api_key())
+                    .body(body.clone())
+            })
+            .await?;
 
         let response: InstallationResponse =
-            response.json().await.map_err(|e| Error::Response(API, e))?;
+            serde_json::from_slice(response.body()).map_err(|e| Error::Response(API, e))?;
+
+        Ok(Self {
+            fid: response.fid,
+            refresh_token: response.refresh_token,
+            auth_token: response.auth_token.into_token(API)?,
+        })
+    }
+
+    /// the auth token, renewed first if it is about to expire
+    pub async fn auth_token<'a>(
+        &mut self,
+        http: impl Into<Http<'a>>,
+        firebase: &FirebaseConfig,
+    ) -> Result<&str, Error> {
+        let http = http.into();
+        if self.auth_token.is_expiring(SystemTime::now()) {
+            self.renew_auth_token(&http, firebase).await?;
+        }
+
+        Ok(&self.auth_token.value)
+    }
+
+    /// get a new auth token for the installation using its refresh token
+    pub async fn renew_auth_token<'a>(
+        &mut self,
+        http: impl Into<Http<'a>>,
+        firebase: &FirebaseConfig,
+    ) -> Result<(), Error> {
+        const API: &str = "Firebase installation auth token";
+
+        let http = http.into();
+        let request = GenerateAuthTokenRequest {
+            installation: InstallationMetadata {
+                app_id: firebase.app_id(),
+                sdk_version: SDK_VERSION,
+            },
+        };
+
+        let project_id = firebase.project_id();
+        let fid = &self.fid;
+        let url =
+            format!("{INSTALL_API}/projects/{project_id}/installations/{fid}/authTokens:generate");
+        let body = serde_json::to_vec(&request).expect("auth token request should serialize");
+        let response = http
+            .send(API, Idempotency::Idempotent, || {
+                http::Request::post(&url)
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .header(http::header::AUTHORIZATION, self.authorization()?)
+                    .header("x-goog-api-key", firebase.api_key())
+                    .body(body.clone())
+            })
+            .await?;
+
+        let response: AuthTokenResponse =
+            serde_json::from_slice(response.body()).map_err(|e| Error::Response(API, e))?;
+
+        self.auth_token = response.into_token(API)?;
+        Ok(())
+    }
+
+    /// the `Authorization` header of calls made with the refresh token
+    fn authorization(&self) -> Result<http::HeaderValue, http::Error> {
+        let value = Zeroizing::new(format!("FIS_v2 {}", self.refresh_token));
+        crate::api::sensitive_header(&value)
+    }
+
+    /// delete the installation, invalidating its auth tokens
+    pub async fn delete<'a>(
+        &self,
+        http: impl Into<Http<'a>>,
+        firebase: &FirebaseConfig,
+    ) -> Result<(), Error> {
+        const API: &str = "Firebase installation deletion";
 
-        Ok(response.auth_token)
+        let http = http.into();
+        let project_id = firebase.project_id();
+        let fid = &self.fid;
+        let url = format!("{INSTALL_API}/projects/{project_id}/installations/{fid}");
+        http.send(API, Idempotency::Idempotent, || {
+            http::Request::delete(&url)
+                .header(http::header::AUTHORIZATION, self.authorization()?)
+                .header("x-goog-api-key", firebase.api_key())
+                .body(Vec::new())
+        })
+        .await?;
+        Ok(())
     }
 }
 
@@ -1,12 +1,16 @@
+#[allow(clippy::all)]
 pub mod contract {
     include!(concat!(env!("OUT_DIR"), "/checkin_proto.rs"));
 }
 
-use crate::Error;
+use crate::api::Idempotency;
+use crate::{DeviceProfile, Error, Http};
 use prost::bytes::BufMut;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio_rustls::rustls::pki_types::ServerName;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 fn require_some<T>(value: Option<T>, reason: &'static str) -> Result<T, Error> {
     match value {
@@ -18,59 +22,131 @@ fn require_some<T>(value: Option<T>, reason: &'static str) -> Result<T, Error> {
 const CHECKIN_URL: &str = "https://android.clients.google.com/checkin";
 const REGISTER_URL: &str = "https://android.clients.google.com/c2dm/register3";
 
-// Normal JSON serialization will lose precision and change the number, so we must
-// force the i64/u64 to serialize to string.
+/// Wiped from memory when dropped. The `Debug` output leaves out the security token.
+///
+/// The android id and security token serialize as strings, since JSON numbers would lose
+/// precision. Deserialization fails if the session doesn't pass [`Session::validate`].
 #[serde_as]
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+#[serde(try_from = "UncheckedSession")]
 pub struct Session {
     #[serde_as(as = "serde_with::DisplayFromStr")]
     pub android_id: i64,
 
     #[serde_as(as = "serde_with::DisplayFromStr")]
     pub security_token: u64,
+
+    /// The Chrome install the session checks in and connects as. Sessions stored by older
+    /// versions get the default profile, which is what they were created with.
+    #[serde(default)]
+    #[zeroize(skip)]
+    pub device: DeviceProfile,
+
+    /// Digest of the settings the last checkin handed out, sent back on the next checkin so the
+    /// server only reports what changed since
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+/// Wire form of [`Session`], before validation
+#[serde_as]
+#[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
+struct UncheckedSession {
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    android_id: i64,
+
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    security_token: u64,
+
+    #[serde(default)]
+    #[zeroize(skip)]
+    device: DeviceProfile,
+
+    #[serde(default)]
+    digest: Option<String>,
+}
+
+impl TryFrom<UncheckedSession> for Session {
+    type Error = Error;
+
+    fn try_from(mut value: UncheckedSession) -> Result<Self, Self::Error> {
+        let session = Self {
+            android_id: value.android_id,
+            security_token: value.security_token,
+            device: std::mem::take(&mut value.device),
+            digest: value.digest.take(),
+        };
+        session.validate()?;
+        Ok(session)
+    }
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("android_id", &self.android_id)
+            .field("security_token", &crate::REDACTED)
+            .field("device", &self.device)
+            .field("digest", &self.digest)
+            .finish()
+    }
 }
 
 impl Session {
+    /// check that the session holds credentials the check-in service could have issued
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.android_id == 0 {
+            return Err(Error::InvalidRegistration("android id is zero"));
+        }
+
+        if self.security_token == 0 {
+            return Err(Error::InvalidRegistration("security token is zero"));
+        }
+
+        Ok(())
+    }
+
     async fn request(
-        http: &reqwest::Client,
+        http: &Http<'_>,
+        device: &DeviceProfile,
         android_id: Option<i64>,
         security_token: Option<u64>,
-    ) -> Result<Self, Error> {
+        digest: Option<&str>,
+    ) -> Result<(Self, CheckinResult), Error> {
         use prost::Message;
 
         let request = contract::AndroidCheckinRequest {
             version: Some(3),
             id: android_id,
             security_token,
+            digest: digest.map(String::from),
             user_serial_number: Some(0),
-            checkin: contract::AndroidCheckinProto {
-                r#type: Some(3),
-                chrome_build: Some(contract::ChromeBuildProto {
-                    platform: Some(2),
-                    channel: Some(1),
-                    chrome_version: Some(String::from("63.0.3234.0")),
-                }),
-                ..Default::default()
-            },
+            checkin: device.checkin_proto(),
+            locale: device.locale().map(String::from),
+            time_zone: device.time_zone().map(String::from),
             ..Default::default()
         };
 
         const API_NAME: &str = "GCM checkin";
 
+        // The first checkin creates the device, later ones only refresh it
+        let idempotency = match android_id {
+            Some(_) => Idempotency::Idempotent,
+            None => Idempotency::NonIdempotent,
+        };
+
+        let body = request.encode_to_vec();
         let response = http
-            .post(CHECKIN_URL)
-            .body(request.encode_to_vec())
-            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
-            .send()
-            .await
-            .map_err(|e| Error::Request(API_NAME, e))?;
-
-        let response_bytes = response
-            .bytes()
-            .await
-            .map_err(|e| Error::Response(API_NAME, e))?;
-        let response = contract::AndroidCheckinResponse::decode(response_bytes)
+            .send(API_NAME, idempotency, || {
+                http::Request::post(CHECKIN_URL)
+                    .header(http::header::CONTENT_TYPE, "application/x-protobuf")
+                    .body(body.clone())
+            })
+            .await?;
+
+        let response = contract::AndroidCheckinResponse::decode(response.into_body())
             .map_err(|e| Error::ProtobufDecode("android checkin response", e))?;
+        let received_at = SystemTime::now();
 
         let android_id = require_some(response.android_id, "response is missing android id")?;
 
@@ -84,69 +160,160 @@ impl Session {
             "response is missing security token",
         )?;
 
-        Ok(Self {
+        let result = CheckinResult {
+            received_at,
+            server_time: response
+                .time_msec
+                .and_then(|ms| UNIX_EPOCH.checked_add(Duration::from_millis(ms.try_into().ok()?))),
+            digest: response.digest,
+            settings_diff: response.settings_diff.unwrap_or_default(),
+            settings: response
+                .setting
+                .into_iter()
+                .map(|s| GservicesSetting {
+                    name: s.name,
+                    value: s.value,
+                })
+                .collect(),
+            deleted_settings: response.delete_setting,
+        };
+
+        let session = Self {
             android_id,
             security_token,
-        })
+            device: device.clone(),
+            digest: result.digest.clone().or_else(|| digest.map(String::from)),
+        };
+        Ok((session, result))
     }
 
     /// check in to the device registration service, possibly obtaining a new security token
-    pub async fn checkin(&self, http: &reqwest::Client) -> Result<CheckedSession, Error> {
-        let r = Self::request(http, Some(self.android_id), Some(self.security_token)).await?;
-        Ok(CheckedSession(r))
+    pub async fn checkin<'a>(&self, http: impl Into<Http<'a>>) -> Result<CheckedSession, Error> {
+        let http = http.into();
+        let (session, result) = Self::request(
+            &http,
+            &self.device,
+            Some(self.android_id),
+            Some(self.security_token),
+            self.digest.as_deref(),
+        )
+        .await?;
+        Ok(CheckedSession { session, result })
     }
 
     /// check in to the device registration service for the first time
     pub fn create<'a>(
-        http: &'a reqwest::Client,
+        http: impl Into<Http<'a>>,
     ) -> impl std::future::Future<Output = Result<Self, Error>> + 'a {
-        Self::request(http, None, None)
+        let checked = Self::create_with_device(http, DeviceProfile::default());
+        async move { Ok(checked.await?.into_parts().0) }
     }
 
-    pub async fn request_token(&self, app_id: &str) -> Result<String, Error> {
+    /// check in for the first time as the given Chrome install, along with what the checkin
+    /// reported
+    pub fn create_with_device<'a>(
+        http: impl Into<Http<'a>>,
+        device: DeviceProfile,
+    ) -> impl std::future::Future<Output = Result<CheckedSession, Error>> + 'a {
+        let http = http.into();
+        async move {
+            let (session, result) = Self::request(&http, &device, None, None, None).await?;
+            Ok(CheckedSession { session, result })
+        }
+    }
+
+    pub async fn request_token<'a>(
+        &self,
+        http: impl Into<Http<'a>>,
+        app_id: &str,
+    ) -> Result<String, Error> {
         /// Server key in URL-safe base64
         const SERVER_KEY: &str =
             "BDOU99-h67HcA6JeFXHbSNMu7e2yNNu3RzoMj8TM4W88jITfq7ZmPvIM1Iv-4_l2LxQcYwhqby2xGpWwzjfAnG4";
 
-        let android_id = self.android_id.to_string();
-        let auth_header = format!("AidLogin {}:{}", &android_id, &self.security_token);
-        let mut params = std::collections::HashMap::with_capacity(4);
-        params.insert("app", "org.chromium.linux");
-        params.insert("X-subtype", app_id);
-        params.insert("device", &android_id);
-        params.insert("sender", SERVER_KEY);
-
         const API_NAME: &str = "GCM registration";
-        let result = reqwest::Client::new()
-            .post(REGISTER_URL)
-            .form(&params)
-            .header(reqwest::header::AUTHORIZATION, auth_header)
-            .send()
-            .await
-            .map_err(|e| Error::Request(API_NAME, e))?;
+        let params = [("X-subtype", app_id), ("sender", SERVER_KEY)];
+        let (_, token) = self
+            .register3(&http.into(), API_NAME, Idempotency::NonIdempotent, &params)
+            .await?;
+        Ok(token)
+    }
 
-        let response_text = result
-            .text()
-            .await
-            .map_err(|e| Error::Response(API_NAME, e))?;
+    /// unregister the token that [`Session::request_token`] issued for `app_id`
+    pub async fn delete_token<'a>(
+        &self,
+        http: impl Into<Http<'a>>,
+        app_id: &str,
+    ) -> Result<(), Error> {
+        const API_NAME: &str = "GCM unregistration";
+        let params = [("delete", "true"), ("X-subtype", app_id)];
+
+        match self
+            .register3(&http.into(), API_NAME, Idempotency::Idempotent, &params)
+            .await?
+        {
+            (key, _) if key == "deleted" => Ok(()),
+            _ => Err(Error::DependencyFailure(API_NAME, "malformed response")),
+        }
+    }
 
-        const ERR_EOF: Error = Error::DependencyFailure(API_NAME, "malformed response");
+    /// calls the registration endpoint on behalf of our fake Chrome app, returning the
+    /// `key=value` pair it responds with
+    async fn register3(
+        &self,
+        http: &Http<'_>,
+        api_name: &'static str,
+        idempotency: Idempotency,
+        params: &[(&str, &str)],
+    ) -> Result<(String, String), Error> {
+        let android_id = self.android_id.to_string();
+        let auth_header =
+            Zeroizing::new(format!("AidLogin {}:{}", &android_id, &self.security_token));
+        let mut auth_header = http::HeaderValue::from_str(&auth_header)
+            .expect("numeric credentials should form a valid header");
+        auth_header.set_sensitive(true);
+        let mut form = Vec::with_capacity(params.len() + 2);
+        form.push(("app", self.device.app()));
+        form.push(("device", &android_id));
+        form.extend(params.iter().copied());
+        let body = serde_urlencoded::to_string(&form).expect("form fields should encode");
+
+        let response = http
+            .send(api_name, idempotency, || {
+                http::Request::post(REGISTER_URL)
+                    .header(
+                        http::header::CONTENT_TYPE,
+                        "application/x-www-form-urlencoded",
+                    )
+                    .header(http::header::AUTHORIZATION, auth_header.clone())
+                    .body(body.clone().into_bytes())
+            })
+            .await?;
+
+        let response_text =
+            std::str::from_utf8(response.body()).map_err(|e| Error::Utf8(api_name, e))?;
+
+        let err_eof = Error::DependencyFailure(api_name, "malformed response");
 
         let mut tokens = response_text.split('=');
-        match tokens.next() {
+        let key = match tokens.next() {
             Some("Error") => {
-                return Err(Error::DependencyRejection(
-                    API_NAME,
-                    tokens.next().unwrap_or("no reasons given").into(),
-                ))
+                return Err(Error::Api(Box::new(crate::ApiError {
+                    api: api_name,
+                    http_status: response.status(),
+                    code: None,
+                    message: None,
+                    status: tokens.next().map(str::to_owned),
+                    details: Vec::new(),
+                })))
             }
-            None => return Err(ERR_EOF),
-            _ => {}
-        }
+            Some(key) => key.to_owned(),
+            None => return Err(err_eof),
+        };
 
         match tokens.next() {
-            Some(v) => Ok(String::from(v)),
-            None => Err(ERR_EOF),
+            Some(v) => Ok((key, String::from(v))),
+            None => Err(err_eof),
         }
     }
 }
@@ -163,29 +330,87 @@ fn new_tls_initiator() -> tokio_rustls::TlsConnector {
     tokio_rustls::TlsConnector::from(std::sync::Arc::new(config))
 }
 
-pub struct CheckedSession(Session);
+/// What a checkin reported besides the credentials
+#[derive(Clone, Debug)]
+pub struct CheckinResult {
+    /// When the response arrived, by the local clock
+    pub received_at: SystemTime,
+
+    /// The time of day at the server
+    pub server_time: Option<SystemTime>,
+
+    /// Digest of the settings below, fed back on the next checkin
+    pub digest: Option<String>,
+
+    /// Whether `settings` only holds what changed since the digest we sent, rather than
+    /// everything
+    pub settings_diff: bool,
+
+    /// Gservices settings in the order they came, possibly repeating a name
+    pub settings: Vec<GservicesSetting>,
+
+    /// Names of settings to drop, when `settings_diff` is set
+    pub deleted_settings: Vec<String>,
+}
+
+/// A Gservices setting. Names and values are bytes on the wire and not necessarily UTF-8.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GservicesSetting {
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl CheckinResult {
+    /// how far the server clock is ahead of ours, negative if it is behind. Ignores the time
+    /// the response took to arrive.
+    pub fn clock_skew_millis(&self) -> Option<i64> {
+        let millis = |t: SystemTime| {
+            let since = t.duration_since(UNIX_EPOCH).ok()?;
+            i64::try_from(since.as_millis()).ok()
+        };
+        Some(millis(self.server_time?)? - millis(self.received_at)?)
+    }
+}
+
+pub struct CheckedSession {
+    session: Session,
+    result: CheckinResult,
+}
 
 impl CheckedSession {
     const MCS_VERSION: u8 = 41;
     const LOGIN_REQUEST_TAG: u8 = 2;
 
+    /// whether the checkin handed out a new android id, security token or settings digest, in
+    /// which case the session should be saved
     pub fn changed(&self, from: &Session) -> bool {
-        self.0.security_token != from.security_token || self.0.android_id != from.android_id
+        self.session.security_token != from.security_token
+            || self.session.android_id != from.android_id
+            || self.session.digest != from.digest
+    }
+
+    /// the settings and server time the checkin reported
+    pub fn result(&self) -> &CheckinResult {
+        &self.result
+    }
+
+    pub fn into_parts(self) -> (Session, CheckinResult) {
+        (self.session, self.result)
     }
 
     fn new_mcs_login_request(
         &self,
         received_persistent_id: Vec<String>,
     ) -> crate::mcs::LoginRequest {
-        let android_id = self.0.android_id.to_string();
+        let android_id = self.session.android_id.to_string();
         crate::mcs::LoginRequest {
             adaptive_heartbeat: Some(false),
             auth_service: Some(2),
-            auth_token: self.0.security_token.to_string(),
-            id: "chrome-63.0.3234.0".into(),
+            auth_token: self.session.security_token.to_string(),
+            id: self.session.device.login_id(),
             domain: "mcs.android.com".into(),
-            device_id: Some(format!("android-{:x}", self.0.android_id)),
-            network_type: Some(1),
+            device_id: Some(format!("android-{:x}", self.session.android_id)),
+            network_type: Some(self.session.device.network_type().proto()),
             resource: android_id.clone(),
             user: android_id,
             use_rmq2: Some(true),
@@ -230,7 +455,7 @@ impl CheckedSession {
 
         let domain = ServerName::try_from("mtalk.google.com").or(Err(ERR_RESOLVE))?;
 
-        let login_request = self.new_mcs_login_request(received_persistent_id);
+        let mut login_request = self.new_mcs_login_request(received_persistent_id);
 
         let mut login_bytes = bytes::BytesMut::with_capacity(2 + login_request.encoded_len() + 4);
         login_bytes.put_u8(Self::MCS_VERSION);
@@ -238,10 +463,11 @@ impl CheckedSession {
         login_request
             .encode_length_delimited(&mut login_bytes)
             .expect("login request encoding failure");
+        login_request.auth_token.zeroize();
 
-        Self::try_connect(domain.clone(), &login_bytes)
-            .await
-            .map_err(Error::Socket)
+        let result = Self::try_connect(domain.clone(), &login_bytes).await;
+        login_bytes.as_mut().zeroize();
+        result.map_err(Error::Socket)
     }
 }
 
@@ -249,7 +475,7 @@ impl std::ops::Deref for CheckedSession {
     type Target = Session;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.session
     }
 }
 
@@ -268,3 +494,42 @@ impl std::ops::DerefMut for Connection {
         &mut self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(android_id: &str, security_token: &str) -> Result<Session, serde_json::Error> {
+        serde_json::from_value(serde_json::json!({
+            "android_id": android_id,
+            "security_token": security_token,
+        }))
+    }
+
+    #[test]
+    fn loads_sessions_stored_by_older_versions() {
+        let session = load("5123456789012345678", "8765432109876543210").unwrap();
+        assert_eq!(session.android_id, 5_123_456_789_012_345_678);
+        assert_eq!(session.security_token, 8_765_432_109_876_543_210);
+        assert_eq!(session.device, DeviceProfile::default());
+        assert_eq!(session.digest, None);
+    }
+
+    #[test]
+    fn rejects_zero_android_id() {
+        let error = load("0", "8765432109876543210").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid registration: android id is zero"
+        );
+    }
+
+    #[test]
+    fn rejects_zero_security_token() {
+        let error = load("5123456789012345678", "0").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid registration: security token is zero"
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use crate::WebPushKeys;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// FCM keeps undelivered messages for at most 4 weeks, so nothing encrypted to a key that was
+/// retired longer ago than that can still be waiting for us.
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(28 * 24 * 60 * 60);
+
+/// Identifies which key in a [`KeyRing`] decrypted a message
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyId {
+    /// The current key of the ring
+    Current,
+    /// The retired key at this index of [`KeyRing::retired`]
+    Retired(usize),
+}
+
+/// A set of web push keys that was replaced by a newer set
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetiredKeys {
+    pub keys: WebPushKeys,
+
+    /// Seconds since the unix epoch at which the keys were retired
+    pub retired_at: u64,
+}
+
+impl RetiredKeys {
+    /// `None` if the expiry is too far out to represent, i.e. the keys never expire
+    fn expires_at(&self, grace_period: Duration) -> Option<SystemTime> {
+        UNIX_EPOCH
+            .checked_add(Duration::from_secs(self.retired_at))?
+            .checked_add(grace_period)
+    }
+}
+
+/// The web push keys used to decrypt incoming messages.
+///
+/// Messages already queued on the server when the keys are rotated are still encrypted to the
+/// old keys, so retired keys are kept around and tried after the current one until their grace
+/// period lapses.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyRing {
+    current: WebPushKeys,
+    retired: Vec<RetiredKeys>,
+    grace_period: Duration,
+}
+
+impl KeyRing {
+    pub fn new(current: WebPushKeys) -> Self {
+        Self {
+            current,
+            retired: Vec::new(),
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+
+    /// how long a retired key keeps decrypting messages, 4 weeks by default
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// add keys that were retired at the given time
+    pub fn with_retired(mut self, keys: WebPushKeys, retired_at: SystemTime) -> Self {
+        self.retired.push(RetiredKeys {
+            keys,
+            retired_at: unix_seconds(retired_at),
+        });
+        self
+    }
+
+    pub fn current(&self) -> &WebPushKeys {
+        &self.current
+    }
+
+    pub fn retired(&self) -> &[RetiredKeys] {
+        &self.retired
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// make `keys` the current keys, retiring the previous ones as of now
+    pub fn rotate(&mut self, keys: WebPushKeys) {
+        let previous = std::mem::replace(&mut self.current, keys);
+        self.retired.insert(
+            0,
+            RetiredKeys {
+                keys: previous,
+                retired_at: unix_seconds(SystemTime::now()),
+            },
+        );
+    }
+
+    /// drop retired keys whose grace period has lapsed
+    pub fn prune(&mut self) {
+        let now = SystemTime::now();
+        let grace_period = self.grace_period;
+        self.retired
+            .retain(|r| r.expires_at(grace_period).is_none_or(|t| t > now));
+    }
+
+    /// the current key followed by the retired keys still within their grace period, along
+    /// with the time each retired key expires, if ever
+    pub(crate) fn into_usable(
+        self,
+        now: SystemTime,
+    ) -> impl Iterator<Item = (KeyId, WebPushKeys, Option<SystemTime>)> {
+        let grace_period = self.grace_period;
+        std::iter::once((KeyId::Current, self.current, None)).chain(
+            self.retired
+                .into_iter()
+                .enumerate()
+                .map(move |(i, r)| {
+                    let expires_at = r.expires_at(grace_period);
+                    (KeyId::Retired(i), r.keys, expires_at)
+                })
+                .filter(move |(_, _, expires_at)| expires_at.is_none_or(|t| t > now)),
+        )
+    }
+}
+
+impl From<WebPushKeys> for KeyRing {
+    fn from(keys: WebPushKeys) -> Self {
+        Self::new(keys)
+    }
+}
+
+impl From<&WebPushKeys> for KeyRing {
+    fn from(keys: &WebPushKeys) -> Self {
+        Self::new(keys.clone())
+    }
+}
+
+impl From<&KeyRing> for KeyRing {
+    fn from(ring: &KeyRing) -> Self {
+        ring.clone()
+    }
+}
+
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::tests::{data_frame, encrypted_stanza};
+    use crate::{DataMessage, Error, Message, MessageStream};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn new_keys() -> WebPushKeys {
+        WebPushKeys::new().unwrap()
+    }
+
+    /// receives a message encrypted to `keys` through a stream decrypting with `ring`
+    async fn receive(ring: &KeyRing, keys: &WebPushKeys) -> Result<DataMessage, Error> {
+        use tokio_stream::StreamExt;
+
+        let stanza = encrypted_stanza(keys, "0:1%7031b2e6f9fd7ecd", b"walrus");
+        let mut stream = MessageStream::new(std::io::Cursor::new(data_frame(&stanza)), ring);
+        match stream.next().await.unwrap()? {
+            Message::Data(message) => Ok(message),
+            _ => panic!("expected a data message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn decrypts_with_retired_keys_in_grace_period() {
+        let (current, previous, oldest) = (new_keys(), new_keys(), new_keys());
+        let now = SystemTime::now();
+        let ring = KeyRing::new(current.clone())
+            .with_retired(previous.clone(), now - DAY)
+            .with_retired(oldest.clone(), now - 20 * DAY);
+
+        let message = receive(&ring, &current).await.unwrap();
+        assert_eq!(message.key, Some(KeyId::Current));
+        assert_eq!(message.body, &b"walrus"[..]);
+
+        let message = receive(&ring, &previous).await.unwrap();
+        assert_eq!(message.key, Some(KeyId::Retired(0)));
+        assert_eq!(message.body, &b"walrus"[..]);
+
+        let message = receive(&ring, &oldest).await.unwrap();
+        assert_eq!(message.key, Some(KeyId::Retired(1)));
+    }
+
+    #[tokio::test]
+    async fn drops_retired_keys_after_grace_period() {
+        let (current, previous) = (new_keys(), new_keys());
+        let mut ring =
+            KeyRing::new(current).with_retired(previous.clone(), SystemTime::now() - 29 * DAY);
+
+        let result = receive(&ring, &previous).await;
+        assert!(matches!(result, Err(Error::Undecodable(_))));
+
+        // a longer grace period brings the key back, until it lapses too
+        ring = ring.with_grace_period(30 * DAY);
+        let message = receive(&ring, &previous).await.unwrap();
+        assert_eq!(message.key, Some(KeyId::Retired(0)));
+
+        ring.prune();
+        assert_eq!(ring.retired().len(), 1);
+        ring = ring.with_grace_period(DAY);
+        ring.prune();
+        assert!(ring.retired().is_empty());
+    }
+
+    #[tokio::test]
+    async fn overflowing_expiry_never_expires() {
+        let (current, previous) = (new_keys(), new_keys());
+        let mut ring = KeyRing::new(current)
+            .with_retired(previous.clone(), SystemTime::now())
+            .with_grace_period(Duration::MAX);
+
+        let message = receive(&ring, &previous).await.unwrap();
+        assert_eq!(message.key, Some(KeyId::Retired(0)));
+        ring.prune();
+        assert_eq!(ring.retired().len(), 1);
+
+        // as stored by a corrupted or hostile file
+        let mut json = serde_json::to_value(&ring).unwrap();
+        json["retired"][0]["retired_at"] = u64::MAX.into();
+        json["grace_period"] = serde_json::to_value(DAY).unwrap();
+        let mut ring: KeyRing = serde_json::from_str(&json.to_string()).unwrap();
+
+        let message = receive(&ring, &previous).await.unwrap();
+        assert_eq!(message.key, Some(KeyId::Retired(0)));
+        ring.prune();
+        assert_eq!(ring.retired().len(), 1);
+    }
+}
@@ -1,21 +1,80 @@
+#[allow(dead_code, clippy::all)]
 mod mcs {
     include!(concat!(env!("OUT_DIR"), "/mcs_proto.rs"));
 }
 
+mod aesgcm;
+mod agreement;
+mod api;
+mod config;
+mod device;
+#[cfg(feature = "json")]
+mod envelope;
 mod error;
 mod fcm;
 mod firebase;
 mod gcm;
+mod keyring;
 mod push;
 mod register;
+#[cfg(feature = "seal")]
+mod seal;
+mod transport;
 
+pub use agreement::KeyAgreement;
+pub use api::ApiError;
+pub use api::ErrorDetail;
+pub use api::Http;
+pub use api::RetryPolicy;
+pub use config::FirebaseConfig;
+pub use config::FirebaseConfigBuilder;
+pub use device::Channel;
+pub use device::DeviceProfile;
+pub use device::DeviceType;
+pub use device::NetworkType;
+pub use device::Platform;
+#[cfg(feature = "json")]
+pub use envelope::Envelope;
+#[cfg(feature = "json")]
+pub use envelope::FcmOptions;
+#[cfg(feature = "json")]
+pub use envelope::Notification;
 pub use error::Error;
 pub use fcm::WebPushKeys;
+pub use firebase::Installation;
+pub use firebase::InstallationAuthToken;
+pub use gcm::CheckedSession;
+pub use gcm::CheckinResult;
+pub use gcm::GservicesSetting;
 pub use gcm::Session;
+pub use keyring::KeyId;
+pub use keyring::KeyRing;
+pub use keyring::RetiredKeys;
 pub use push::new_heartbeat_ack;
+pub use push::new_selective_ack;
 pub use push::DataMessage;
+pub use push::Decryption;
+pub use push::ExpiryPolicy;
 pub use push::Message;
 pub use push::MessageStream;
 pub use push::MessageTag;
+pub use push::UndecodableMessage;
 pub use register::register;
+pub use register::unregister;
 pub use register::Registration;
+pub use register::RegistrationBuilder;
+pub use register::RegistrationStage;
+pub use register::Unregistration;
+#[cfg(feature = "seal")]
+pub use seal::open;
+#[cfg(feature = "seal")]
+pub use seal::seal;
+#[cfg(feature = "seal")]
+pub use seal::ListenerState;
+#[cfg(feature = "seal")]
+pub use seal::SealingKey;
+pub use transport::HttpTransport;
+pub use transport::TransportError;
+
+/// Stands in for secrets in `Debug` output
+const REDACTED: &str = "<redacted>";
@@ -1,9 +1,13 @@
-use crate::Error;
+use crate::{Error, KeyAgreement, KeyId, KeyRing};
 use bytes::{Bytes, BytesMut};
-use ece::EcKeyComponents;
 use pin_project_lite::pin_project;
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
 
 #[allow(dead_code)]
 #[derive(PartialEq, Debug)]
@@ -32,13 +36,16 @@ impl TryFrom<u8> for MessageTag {
 
     fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
         if value < Self::NumProtoTypes as u8 {
-            Ok(unsafe { std::mem::transmute(value) })
+            Ok(unsafe { std::mem::transmute::<u8, Self>(value) })
         } else {
             Err(value)
         }
     }
 }
 
+// Messages are handed out one at a time, so boxing the data message would only cost an
+// allocation per message
+#[allow(clippy::large_enum_variant)]
 pub enum Message {
     HeartbeatPing,
     Data(DataMessage),
@@ -46,72 +53,320 @@ pub enum Message {
 }
 
 pub struct DataMessage {
-    pub body: Vec<u8>,
+    /// Decrypted payload, or the raw payload of an unencrypted message
+    pub body: Bytes,
     pub persistent_id: Option<String>,
+
+    /// Key/value entries sent alongside the payload, minus the encryption parameters
+    pub app_data: HashMap<String, String>,
+
+    /// The key that decrypted the message, `None` if it was sent unencrypted
+    pub key: Option<KeyId>,
+
+    /// Sender ID, i.e. the Firebase project number
+    pub from: String,
+
+    /// Application the message is addressed to
+    pub category: String,
+
+    /// Registration the message is addressed to
+    pub to: Option<String>,
+
+    /// Messages with the same collapse key replace each other while waiting to be delivered
+    pub collapse_key: Option<String>,
+
+    /// How long the sender asked for the message to be kept for delivery
+    pub ttl: Option<Duration>,
+
+    /// When the message was sent, according to the server clock
+    pub sent: Option<SystemTime>,
+
+    /// How long the message waited on the server before being flushed to us
+    pub queued: Option<Duration>,
+
+    /// The server asked for the message to be acknowledged right away
+    pub immediate_ack: bool,
+
+    pub from_trusted_server: bool,
+
+    /// The message outlived its TTL before reaching us. Only set under [`ExpiryPolicy::Flag`].
+    pub expired: bool,
+}
+
+/// A data message that arrived but could not be decrypted or parsed
+#[derive(Debug)]
+pub struct UndecodableMessage {
+    /// Add this to the received IDs on the next connection so the server stops redelivering
+    /// the message
+    pub persistent_id: Option<String>,
+    pub from: String,
+    pub category: String,
+    pub to: Option<String>,
+
+    /// All key/value entries, including the encryption parameters
+    pub app_data: HashMap<String, String>,
+
+    /// The payload as received, i.e. still encrypted
+    pub raw_data: Option<Bytes>,
+
+    /// Why decoding failed
+    pub error: Error,
+}
+
+impl UndecodableMessage {
+    fn new(message: crate::mcs::DataMessageStanza, error: Error) -> Self {
+        Self {
+            persistent_id: message.persistent_id,
+            from: message.from,
+            category: message.category,
+            to: message.to,
+            app_data: message
+                .app_data
+                .into_iter()
+                .map(|field| (field.key, field.value))
+                .collect(),
+            raw_data: message.raw_data,
+            error,
+        }
+    }
+}
+
+/// Key material of a ring entry, prepared for decryption
+#[derive(Clone)]
+struct DecryptionKey {
+    id: KeyId,
+    public_key: Vec<u8>,
+    agreement: Arc<dyn KeyAgreement>,
+    auth_secret: Zeroizing<Vec<u8>>,
+
+    /// When a retired key stops being tried
+    expires_at: Option<SystemTime>,
+}
+
+impl DecryptionKey {
+    /// takes ownership of the ring's keys rather than copying them
+    fn from_ring(ring: KeyRing) -> Vec<Self> {
+        ring.into_usable(SystemTime::now())
+            .map(|(id, keys, expires_at)| Self {
+                id,
+                public_key: keys.public_key.clone(),
+                auth_secret: Zeroizing::new(keys.auth_secret.clone()),
+                agreement: Arc::new(keys),
+                expires_at,
+            })
+            .collect()
+    }
 }
 
 impl DataMessage {
-    fn decode(eckey: &EcKeyComponents, auth_secret: &[u8], bytes: &[u8]) -> Result<Self, Error> {
-        use base64::engine::general_purpose::URL_SAFE;
-        use base64::Engine;
-        use ece::legacy::AesGcmEncryptedBlock;
+    const CRYPTO_KEY: &'static str = "crypto-key";
+    const ENCRYPTION: &'static str = "encryption";
+
+    fn decode(keys: &[DecryptionKey], bytes: Bytes) -> Result<Self, Error> {
         use prost::Message;
 
-        let message = crate::mcs::DataMessageStanza::decode(bytes)
+        let mut message = crate::mcs::DataMessageStanza::decode(bytes)
             .map_err(|e| Error::ProtobufDecode("FCM data message", e))?;
 
-        let bytes = match message.raw_data {
-            Some(v) => v,
-            None => {
-                return Err(Error::EmptyPayload);
+        // Hand the stanza back along with the error, so it can still be acknowledged
+        let (body, key) = match Self::open(keys, &message) {
+            Ok(Some((body, key))) => (body, Some(key)),
+            Ok(None) => (message.raw_data.take().unwrap_or_default(), None),
+            Err(error) => {
+                return Err(Error::Undecodable(Box::new(UndecodableMessage::new(
+                    message, error,
+                ))))
             }
         };
 
-        let mut kex: Vec<u8> = Vec::default();
-        let mut salt: Vec<u8> = Vec::default();
-        for field in message.app_data {
+        let app_data = message
+            .app_data
+            .into_iter()
+            .filter(|field| field.key != Self::CRYPTO_KEY && field.key != Self::ENCRYPTION)
+            .map(|field| (field.key, field.value))
+            .collect();
+
+        // Negative durations or timestamps don't mean anything, treat them as absent
+        let seconds = |v: Option<i64>| {
+            v.and_then(|v| u64::try_from(v).ok())
+                .map(Duration::from_secs)
+        };
+        Ok(Self {
+            body,
+            persistent_id: message.persistent_id,
+            app_data,
+            key,
+            from: message.from,
+            category: message.category,
+            to: message.to,
+            collapse_key: message.token,
+            ttl: seconds(message.ttl.map(i64::from)),
+            sent: seconds(message.sent).map(|t| UNIX_EPOCH + t),
+            queued: seconds(message.queued.map(i64::from)),
+            immediate_ack: message.immediate_ack.unwrap_or_default(),
+            from_trusted_server: message.from_trusted_server.unwrap_or_default(),
+            expired: false,
+        })
+    }
+
+    /// decrypts the payload, or returns `None` if the message was sent in the clear
+    fn open(
+        keys: &[DecryptionKey],
+        message: &crate::mcs::DataMessageStanza,
+    ) -> Result<Option<(Bytes, KeyId)>, Error> {
+        use base64::engine::general_purpose::URL_SAFE;
+        use base64::Engine;
+
+        let mut kex = None;
+        let mut salt = None;
+        for field in &message.app_data {
             match field.key.as_str() {
-                "crypto-key" => {
+                Self::CRYPTO_KEY => {
                     // crypto_key format: dh=abc...
-                    kex = URL_SAFE
-                        .decode(&field.value[3..])
-                        .map_err(|e| Error::Base64Decode("FCM message crypto-key", e))?;
-
-                    if !salt.is_empty() {
-                        break;
-                    }
+                    let value = field
+                        .value
+                        .strip_prefix("dh=")
+                        .ok_or(Error::MissingCryptoMetadata(Self::CRYPTO_KEY))?;
+                    kex = Some(
+                        URL_SAFE
+                            .decode(value)
+                            .map_err(|e| Error::Base64Decode("FCM message crypto-key", e))?,
+                    );
                 }
-                "encryption" => {
+                Self::ENCRYPTION => {
                     // encryption format: salt=abc...
-                    salt = URL_SAFE
-                        .decode(&field.value[5..])
-                        .map_err(|e| Error::Base64Decode("FCM message encryption params", e))?;
-
-                    if !kex.is_empty() {
-                        break;
-                    }
+                    let value = field
+                        .value
+                        .strip_prefix("salt=")
+                        .ok_or(Error::MissingCryptoMetadata(Self::ENCRYPTION))?;
+                    salt =
+                        Some(URL_SAFE.decode(value).map_err(|e| {
+                            Error::Base64Decode("FCM message encryption params", e)
+                        })?);
                 }
                 _ => {}
             }
         }
 
-        if kex.is_empty() {
-            return Err(Error::MissingCryptoMetadata("crypto-key"));
-        } else if salt.is_empty() {
-            return Err(Error::MissingCryptoMetadata("encryption"));
+        // Messages without any encryption parameters were sent in the clear, typically as plain
+        // key/value app data
+        match (kex, salt) {
+            (None, None) => Ok(None),
+            (None, Some(_)) => Err(Error::MissingCryptoMetadata(Self::CRYPTO_KEY)),
+            (Some(_), None) => Err(Error::MissingCryptoMetadata(Self::ENCRYPTION)),
+            (Some(kex), Some(salt)) => {
+                let bytes = message.raw_data.as_deref().ok_or(Error::EmptyPayload)?;
+                Self::decrypt(keys, &kex, &salt, bytes).map(Some)
+            }
         }
+    }
 
-        // The record size default is 4096 and doesn't seem to be overridden for FCM.
-        const RECORD_SIZE: u32 = 4096;
-        const OPERATION: &str = "message decryption";
-        let block = AesGcmEncryptedBlock::new(&kex, &salt, RECORD_SIZE, bytes)
-            .map_err(|e| Error::Crypto(OPERATION, e))?;
-        let body = ece::legacy::decrypt_aesgcm(eckey, auth_secret, &block)
-            .map_err(|e| Error::Crypto(OPERATION, e))?;
-        Ok(Self {
-            body,
-            persistent_id: message.persistent_id,
-        })
+    /// how long ago the message was sent, falling back to how long it was queued on the server
+    /// when the send time is unknown
+    ///
+    /// The send time comes from the server clock, so this is skewed by however far our clock is
+    /// off.
+    pub fn age(&self, now: SystemTime) -> Option<Duration> {
+        match self.sent {
+            Some(sent) => Some(now.duration_since(sent).unwrap_or_default()),
+            None => self.queued,
+        }
+    }
+
+    /// whether the message is older than its TTL. A TTL of zero means "deliver now or never",
+    /// which we can't judge after the fact, so such messages never count as expired.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        match (self.ttl, self.age(now)) {
+            (Some(ttl), Some(age)) => !ttl.is_zero() && age > ttl,
+            _ => false,
+        }
+    }
+
+    fn decrypt(
+        keys: &[DecryptionKey],
+        kex: &[u8],
+        salt: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<(Bytes, KeyId), Error> {
+        // A key mismatch only shows up as a failed decryption, so try the current key first and
+        // fall back to the retired ones. If none fit, report why the current key failed.
+        let now = SystemTime::now();
+        let mut first_error = None;
+        for key in keys {
+            if key.expires_at.is_some_and(|t| t <= now) {
+                continue;
+            }
+
+            // Decryption happens in place and leaves garbage behind when it fails
+            let mut buffer = ciphertext.to_vec();
+            let result = crate::aesgcm::decrypt(
+                key.agreement.as_ref(),
+                &key.public_key,
+                &key.auth_secret,
+                kex,
+                salt,
+                &mut buffer,
+            );
+
+            match result {
+                Ok(plaintext) => return Ok((Bytes::from(buffer).slice(plaintext), key.id)),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        // The current key never expires, so at least one attempt was made
+        Err(first_error.expect("current key should have been tried"))
+    }
+}
+
+/// What to do with data messages that outlived their TTL, e.g. after a long time offline
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ExpiryPolicy {
+    /// Deliver every message without checking its age
+    #[default]
+    Ignore,
+    /// Deliver every message, setting [`DataMessage::expired`] on expired ones
+    Flag,
+    /// Discard expired messages, acknowledging them right away so the server doesn't deliver
+    /// them again on the next connection
+    Drop,
+}
+
+/// Where data messages get decrypted
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Decryption {
+    /// Decrypt inside `poll_next`, on whichever thread polls the stream
+    #[default]
+    Inline,
+    /// Decrypt on tokio's blocking pool, with at most this many messages in flight. Messages are
+    /// still delivered in the order they arrived.
+    Blocking(NonZeroUsize),
+}
+
+/// A frame that was read off the socket but not handed out yet
+enum Pending {
+    Decrypting(tokio::task::JoinHandle<Result<DataMessage, Error>>),
+    /// A frame that arrived behind data messages still being decrypted
+    Ready(Option<Box<Message>>),
+}
+
+impl std::future::Future for Pending {
+    type Output = Option<Result<Message, Error>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &mut *self {
+            Self::Ready(message) => Poll::Ready(message.take().map(|m| Ok(*m))),
+            Self::Decrypting(task) => match ready!(Pin::new(task).poll(cx)) {
+                Ok(result) => Poll::Ready(Some(result.map(Message::Data))),
+                Err(e) => match e.try_into_panic() {
+                    Ok(panic) => std::panic::resume_unwind(panic),
+                    // the runtime is shutting down, there's nobody left to read the message
+                    Err(_) => Poll::Ready(None),
+                },
+            },
+        }
     }
 }
 
@@ -119,30 +374,71 @@ pin_project! {
     pub struct MessageStream<T> {
         #[pin]
         inner: T,
-        eckey: EcKeyComponents,
-        auth_secret: Vec<u8>,
+        keys: Arc<[DecryptionKey]>,
+        decryption: Decryption,
+        // frames read ahead while their data messages are decrypted elsewhere
+        pending: VecDeque<Pending>,
+        expiry_policy: ExpiryPolicy,
         bytes_required: usize,
         receive_buffer: BytesMut,
+        // acknowledgements the server asked for, still to be written out
+        ack_buffer: BytesMut,
     }
 }
 
 impl MessageStream<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
-    pub fn wrap(connection: crate::gcm::Connection, keys: &crate::fcm::WebPushKeys) -> Self {
+    /// `keys` is either a [`KeyRing`] or the [`crate::WebPushKeys`] of a registration
+    pub fn wrap(connection: crate::gcm::Connection, keys: impl Into<KeyRing>) -> Self {
         Self::new(connection.0, keys)
     }
 }
 
 impl<T> MessageStream<T> {
-    fn new(inner: T, keys: &crate::fcm::WebPushKeys) -> Self {
+    /// read messages from any byte stream that speaks MCS, e.g. an in-memory one in tests
+    pub fn new(inner: T, keys: impl Into<KeyRing>) -> Self {
         Self {
             inner,
-            eckey: EcKeyComponents::new(keys.private_key.clone(), keys.public_key.clone()),
-            auth_secret: keys.auth_secret.clone(),
+            keys: DecryptionKey::from_ring(keys.into()).into(),
+            decryption: Decryption::default(),
+            pending: VecDeque::new(),
+            expiry_policy: ExpiryPolicy::default(),
             bytes_required: 2,
             receive_buffer: BytesMut::with_capacity(1024),
+            ack_buffer: BytesMut::new(),
         }
     }
 
+    /// check data messages against their TTL, see [`ExpiryPolicy`]
+    pub fn with_expiry_policy(mut self, policy: ExpiryPolicy) -> Self {
+        self.expiry_policy = policy;
+        self
+    }
+
+    /// decrypt data messages off the polling thread, see [`Decryption`]
+    ///
+    /// Worth it when a backlog of messages arrives at once, e.g. right after login, which
+    /// would otherwise keep the reactor thread busy.
+    pub fn with_decryption(mut self, decryption: Decryption) -> Self {
+        self.decryption = decryption;
+        self
+    }
+
+    /// perform the key agreement for every key of the ring through `agreement`, e.g. when the
+    /// private keys are held by a separate key service. The private keys in the ring are then
+    /// never used and may be left empty, see [`crate::WebPushKeys::validate`].
+    pub fn with_key_agreement(mut self, agreement: impl KeyAgreement + 'static) -> Self {
+        let agreement: Arc<dyn KeyAgreement> = Arc::new(agreement);
+        self.keys = self
+            .keys
+            .iter()
+            .map(|key| DecryptionKey {
+                agreement: agreement.clone(),
+                ..key.clone()
+            })
+            .collect();
+        self
+    }
+
     /// returns a decoded protobuf varint or a state change if there is insufficient data
     fn try_read_varint<'a>(mut bytes: impl Iterator<Item = &'a u8>) -> (usize, usize) {
         let mut result = 0;
@@ -172,13 +468,60 @@ impl<T> MessageStream<T> {
     }
 }
 
-impl<T> tokio_stream::Stream for MessageStream<T>
+impl<T> MessageStream<T>
 where
-    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    T: tokio::io::AsyncWrite + Unpin,
 {
-    type Item = Result<Message, Error>;
+    /// writes out queued acknowledgements, keeping whatever the socket doesn't take yet
+    fn poll_write_acks(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        use bytes::Buf;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while !self.ack_buffer.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.ack_buffer))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+
+            self.ack_buffer.advance(written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// sends the acknowledgement the message asked for and applies the expiry policy, returning
+    /// `None` if the message is to be dropped
+    fn accept(&mut self, mut message: DataMessage, cx: &mut Context<'_>) -> Option<DataMessage> {
+        if self.expiry_policy != ExpiryPolicy::Ignore {
+            message.expired = message.is_expired(SystemTime::now());
+        }
+
+        let drop = message.expired && self.expiry_policy == ExpiryPolicy::Drop;
+        if let (true, Some(id)) = (message.immediate_ack || drop, &message.persistent_id) {
+            let ack = new_selective_ack(vec![id.clone()]);
+            self.ack_buffer.extend_from_slice(&ack);
+
+            // errors resurface on the next poll
+            let _ = self.poll_write_acks(cx);
+        }
+
+        if drop {
+            log::debug!("Dropping expired message {:?}", message.persistent_id);
+            return None;
+        }
+
+        Some(message)
+    }
+}
+
+impl<T> MessageStream<T>
+where
+    T: tokio::io::AsyncRead + Unpin,
+{
+    /// reads the next frame off the socket, returning its tag and contents
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(u8, Bytes), Error>>> {
         use bytes::Buf;
         use std::future::Future;
         use tokio::io::AsyncReadExt;
@@ -187,8 +530,7 @@ where
             let mut bytes = self.receive_buffer.iter();
             if let Some(tag_value) = bytes.next() {
                 let tag_value = *tag_value;
-                let tag = MessageTag::try_from(tag_value);
-                if matches!(tag, Ok(MessageTag::Close)) {
+                if matches!(MessageTag::try_from(tag_value), Ok(MessageTag::Close)) {
                     self.bytes_required = 0;
                     self.receive_buffer.clear();
                     return Poll::Ready(None);
@@ -204,24 +546,13 @@ where
                     self.bytes_required = 2;
 
                     self.receive_buffer.advance(offset);
-                    let bytes = self.receive_buffer.split_to(size);
-                    return Poll::Ready(Some(Ok(match tag {
-                        Ok(MessageTag::DataMessageStanza) => {
-                            match DataMessage::decode(&self.eckey, &self.auth_secret, &bytes) {
-                                Err(e) => return Poll::Ready(Some(Err(e))),
-                                Ok(m) => Message::Data(m),
-                            }
-                        }
-                        Ok(MessageTag::HeartbeatPing) => Message::HeartbeatPing,
-                        _ => Message::Other(tag_value, bytes.into()),
-                    })));
+                    let bytes = self.receive_buffer.split_to(size).freeze();
+                    return Poll::Ready(Some(Ok((tag_value, bytes))));
                 }
 
                 // ensure buffer can contain at least the current message
-                let capacity = self.receive_buffer.capacity();
-                if bytes_required > capacity {
-                    self.receive_buffer.reserve(bytes_required - capacity);
-                }
+                let len = self.receive_buffer.len();
+                self.receive_buffer.reserve(bytes_required - len);
 
                 self.bytes_required = bytes_required;
             } else if self.bytes_required == 0 {
@@ -259,6 +590,86 @@ where
     }
 }
 
+impl<T> tokio_stream::Stream for MessageStream<T>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        use std::future::Future;
+
+        // a pending write only delays acknowledgements, don't hold up reading for it
+        if let Poll::Ready(Err(e)) = self.poll_write_acks(cx) {
+            return Poll::Ready(Some(Err(Error::Socket(e))));
+        }
+
+        loop {
+            // offloaded messages are handed out in the order they arrived
+            if let Some(pending) = self.pending.front_mut() {
+                if let Poll::Ready(result) = Pin::new(pending).poll(cx) {
+                    self.pending.pop_front();
+                    match result {
+                        Some(Ok(Message::Data(m))) => match self.accept(m, cx) {
+                            Some(m) => return Poll::Ready(Some(Ok(Message::Data(m)))),
+                            None => continue,
+                        },
+                        result => return Poll::Ready(result),
+                    }
+                }
+            }
+
+            // the front of the queue was polled above, so it wakes us once it's done
+            if let Decryption::Blocking(concurrency) = self.decryption {
+                if self.pending.len() >= concurrency.get() {
+                    return Poll::Pending;
+                }
+            }
+
+            let frame = match self.as_mut().poll_frame(cx) {
+                Poll::Ready(None) if !self.pending.is_empty() => return Poll::Pending,
+                Poll::Ready(frame) => frame,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let (tag_value, bytes) = match frame {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            };
+
+            let message = match MessageTag::try_from(tag_value) {
+                Ok(MessageTag::DataMessageStanza) => match self.decryption {
+                    Decryption::Inline => match DataMessage::decode(&self.keys, bytes) {
+                        Err(e) => return Poll::Ready(Some(Err(e))),
+                        Ok(m) => match self.accept(m, cx) {
+                            Some(m) => Message::Data(m),
+                            None => continue,
+                        },
+                    },
+                    Decryption::Blocking(_) => {
+                        let keys = self.keys.clone();
+                        let task =
+                            tokio::task::spawn_blocking(move || DataMessage::decode(&keys, bytes));
+                        self.pending.push_back(Pending::Decrypting(task));
+                        continue;
+                    }
+                },
+                Ok(MessageTag::HeartbeatPing) => Message::HeartbeatPing,
+                _ => Message::Other(tag_value, bytes),
+            };
+
+            if self.pending.is_empty() {
+                return Poll::Ready(Some(Ok(message)));
+            }
+
+            // don't overtake the data messages still being decrypted
+            self.pending
+                .push_back(Pending::Ready(Some(Box::new(message))));
+        }
+    }
+}
+
 impl<T> std::ops::Deref for MessageStream<T> {
     type Target = T;
 
@@ -273,6 +684,32 @@ impl<T> std::ops::DerefMut for MessageStream<T> {
     }
 }
 
+/// Writes go through the stream rather than straight to the connection, so they can't split
+/// an acknowledgement the stream is sending on its own.
+impl<T> tokio::io::AsyncWrite for MessageStream<T>
+where
+    T: tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        ready!(self.poll_write_acks(cx))?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_write_acks(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_write_acks(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
 pub fn new_heartbeat_ack() -> BytesMut {
     use bytes::BufMut;
 
@@ -284,3 +721,325 @@ pub fn new_heartbeat_ack() -> BytesMut {
 
     bytes
 }
+
+/// acknowledges receipt of the messages with the given persistent IDs, so the server won't
+/// redeliver them
+pub fn new_selective_ack(persistent_ids: Vec<String>) -> BytesMut {
+    use bytes::BufMut;
+
+    /// Extension ID of a selective ack, see `Extension` in mcs.proto
+    const SELECTIVE_ACK: i32 = 12;
+
+    let ack = crate::mcs::SelectiveAck { id: persistent_ids };
+    let iq = crate::mcs::IqStanza {
+        r#type: crate::mcs::iq_stanza::IqType::Set as i32,
+        extension: Some(crate::mcs::Extension {
+            id: SELECTIVE_ACK,
+            data: prost::Message::encode_to_vec(&ack),
+        }),
+        ..Default::default()
+    };
+
+    let mut bytes = BytesMut::with_capacity(prost::Message::encoded_len(&iq) + 5);
+    bytes.put_u8(MessageTag::IqStanza as u8);
+    prost::Message::encode_length_delimited(&iq, &mut bytes)
+        .expect("selective ack serialization should succeed");
+
+    bytes
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::WebPushKeys;
+
+    /// a data message encrypted to `keys` the way FCM sends it
+    pub(crate) fn encrypted_stanza(
+        keys: &WebPushKeys,
+        persistent_id: &str,
+        body: &[u8],
+    ) -> crate::mcs::DataMessageStanza {
+        use base64::engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD};
+        use base64::Engine;
+
+        let block = ece::legacy::encrypt_aesgcm(&keys.public_key, &keys.auth_secret, body).unwrap();
+
+        // The headers come unpadded and with a record size, FCM sends neither
+        let app_data = block
+            .headers(None)
+            .into_iter()
+            .map(|(name, value)| {
+                let (key, param) = match name {
+                    "Crypto-Key" => ("crypto-key", "dh="),
+                    _ => ("encryption", "salt="),
+                };
+                let value = value.trim_start_matches(param).split(';').next().unwrap();
+                let value = URL_SAFE.encode(URL_SAFE_NO_PAD.decode(value).unwrap());
+                crate::mcs::AppData {
+                    key: key.to_owned(),
+                    value: format!("{param}{value}"),
+                }
+            })
+            .collect();
+
+        crate::mcs::DataMessageStanza {
+            persistent_id: Some(persistent_id.to_owned()),
+            from: "1001234567890".to_owned(),
+            category: "org.chromium.linux".to_owned(),
+            app_data,
+            raw_data: Some(URL_SAFE_NO_PAD.decode(block.body()).unwrap().into()),
+            ..Default::default()
+        }
+    }
+
+    /// the frame a data message arrives in
+    pub(crate) fn data_frame(stanza: &crate::mcs::DataMessageStanza) -> Vec<u8> {
+        let mut frame = vec![MessageTag::DataMessageStanza as u8];
+        prost::Message::encode_length_delimited(stanza, &mut frame).unwrap();
+        frame
+    }
+
+    /// an unencrypted data message
+    fn plain_stanza(persistent_id: &str) -> crate::mcs::DataMessageStanza {
+        crate::mcs::DataMessageStanza {
+            persistent_id: Some(persistent_id.to_owned()),
+            from: "1001234567890".to_owned(),
+            category: "org.chromium.linux".to_owned(),
+            raw_data: Some(Bytes::from_static(b"walrus")),
+            ..Default::default()
+        }
+    }
+
+    /// a stream over one end of an in-memory connection, along with the server's end
+    fn connect(
+        keys: impl Into<KeyRing>,
+    ) -> (
+        MessageStream<tokio::io::DuplexStream>,
+        tokio::io::DuplexStream,
+    ) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        (MessageStream::new(client, keys), server)
+    }
+
+    async fn send(server: &mut tokio::io::DuplexStream, stanza: &crate::mcs::DataMessageStanza) {
+        use tokio::io::AsyncWriteExt;
+
+        server.write_all(&data_frame(stanza)).await.unwrap();
+    }
+
+    async fn next_data(stream: &mut MessageStream<tokio::io::DuplexStream>) -> DataMessage {
+        use tokio_stream::StreamExt;
+
+        match stream.next().await.unwrap().unwrap() {
+            Message::Data(message) => message,
+            _ => panic!("expected a data message"),
+        }
+    }
+
+    /// reads the next selective ack the stream sent, returning the IDs it acknowledges
+    async fn read_ack(server: &mut tokio::io::DuplexStream) -> Vec<String> {
+        use prost::Message;
+        use tokio::io::AsyncReadExt;
+
+        assert_eq!(server.read_u8().await.unwrap(), MessageTag::IqStanza as u8);
+        let mut len = Vec::new();
+        loop {
+            let byte = server.read_u8().await.unwrap();
+            len.push(byte);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let len = prost::encoding::decode_varint(&mut len.as_slice()).unwrap();
+        let mut iq = vec![0; len as usize];
+        server.read_exact(&mut iq).await.unwrap();
+
+        let iq = crate::mcs::IqStanza::decode(iq.as_slice()).unwrap();
+        assert_eq!(iq.r#type, crate::mcs::iq_stanza::IqType::Set as i32);
+        let extension = iq.extension.unwrap();
+        assert_eq!(extension.id, 12);
+        crate::mcs::SelectiveAck::decode(extension.data.as_slice())
+            .unwrap()
+            .id
+    }
+
+    /// sent an hour ago with a TTL of a minute
+    fn expire(stanza: &mut crate::mcs::DataMessageStanza) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        stanza.sent = Some(now.as_secs() as i64 - 3600);
+        stanza.ttl = Some(60);
+    }
+
+    #[tokio::test]
+    async fn drops_and_acks_expired_messages() {
+        let (stream, mut server) = connect(WebPushKeys::new().unwrap());
+        let mut stream = stream.with_expiry_policy(ExpiryPolicy::Drop);
+
+        let mut expired = plain_stanza("0:1%7031b2e6f9fd7ecd");
+        expire(&mut expired);
+        send(&mut server, &expired).await;
+        send(&mut server, &plain_stanza("0:2%7031b2e6f9fd7ecd")).await;
+
+        let message = next_data(&mut stream).await;
+        assert_eq!(
+            message.persistent_id.as_deref(),
+            Some("0:2%7031b2e6f9fd7ecd")
+        );
+        assert!(!message.expired);
+        assert_eq!(read_ack(&mut server).await, ["0:1%7031b2e6f9fd7ecd"]);
+    }
+
+    #[tokio::test]
+    async fn flags_expired_messages() {
+        let (stream, mut server) = connect(WebPushKeys::new().unwrap());
+        let mut stream = stream.with_expiry_policy(ExpiryPolicy::Flag);
+
+        let mut expired = plain_stanza("0:1%7031b2e6f9fd7ecd");
+        expire(&mut expired);
+        send(&mut server, &expired).await;
+        send(&mut server, &plain_stanza("0:2%7031b2e6f9fd7ecd")).await;
+
+        let message = next_data(&mut stream).await;
+        assert_eq!(
+            message.persistent_id.as_deref(),
+            Some("0:1%7031b2e6f9fd7ecd")
+        );
+        assert!(message.expired);
+        assert!(!next_data(&mut stream).await.expired);
+
+        // under the default policy, nobody checks
+        let (mut stream, mut server) = connect(WebPushKeys::new().unwrap());
+        send(&mut server, &expired).await;
+        assert!(!next_data(&mut stream).await.expired);
+    }
+
+    #[tokio::test]
+    async fn acks_immediately_when_asked() {
+        let (mut stream, mut server) = connect(WebPushKeys::new().unwrap());
+
+        let mut urgent = plain_stanza("0:2%7031b2e6f9fd7ecd");
+        urgent.immediate_ack = Some(true);
+        send(&mut server, &plain_stanza("0:1%7031b2e6f9fd7ecd")).await;
+        send(&mut server, &urgent).await;
+
+        assert!(!next_data(&mut stream).await.immediate_ack);
+        assert!(next_data(&mut stream).await.immediate_ack);
+
+        // only the message that asked for it is acknowledged
+        assert_eq!(read_ack(&mut server).await, ["0:2%7031b2e6f9fd7ecd"]);
+    }
+
+    #[test]
+    fn encodes_selective_acks() {
+        let ack = new_selective_ack(vec!["0:1%7031b2e6f9fd7ecd".to_owned()]);
+
+        // IqStanza tag and length, type SET, empty id, then extension 12 wrapping the
+        // SelectiveAck with its one id
+        assert_eq!(
+            &ack[..],
+            b"\x07\x20\x10\x01\x1a\x00\x3a\x1a\x08\x0c\x12\x16\x0a\x140:1%7031b2e6f9fd7ecd"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocking_decryption_keeps_arrival_order() {
+        use tokio::io::AsyncWriteExt;
+        use tokio_stream::StreamExt;
+
+        let keys = WebPushKeys::new().unwrap();
+        let (stream, mut server) = connect(&keys);
+        let mut stream =
+            stream.with_decryption(Decryption::Blocking(NonZeroUsize::new(4).unwrap()));
+
+        // a heartbeat and a plain message in the middle of a burst that takes decrypting
+        for i in 0..20 {
+            let id = format!("0:{i}%7031b2e6f9fd7ecd");
+            match i {
+                7 => {
+                    server
+                        .write_all(&[MessageTag::HeartbeatPing as u8, 0])
+                        .await
+                }
+                11 => server.write_all(&data_frame(&plain_stanza(&id))).await,
+                _ => {
+                    let stanza = encrypted_stanza(&keys, &id, id.as_bytes());
+                    server.write_all(&data_frame(&stanza)).await
+                }
+            }
+            .unwrap();
+        }
+        drop(server);
+
+        let mut received = Vec::new();
+        while let Some(message) = stream.next().await {
+            match message.unwrap() {
+                Message::Data(message) => {
+                    let id = message.persistent_id.unwrap();
+                    if message.key.is_some() {
+                        assert_eq!(message.body, id.as_bytes());
+                    }
+                    received.push(id);
+                }
+                Message::HeartbeatPing => received.push("ping".to_owned()),
+                Message::Other(tag, _) => panic!("unexpected frame {tag}"),
+            }
+        }
+
+        let expected: Vec<_> = (0..20)
+            .map(|i| match i {
+                7 => "ping".to_owned(),
+                _ => format!("0:{i}%7031b2e6f9fd7ecd"),
+            })
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    fn stanza(app_data: &[(&str, &str)]) -> Bytes {
+        use prost::Message;
+
+        crate::mcs::DataMessageStanza {
+            persistent_id: Some("0:1700000000000000%7031b2e6f9fd7ecd".to_owned()),
+            from: "1001234567890".to_owned(),
+            category: "org.chromium.linux".to_owned(),
+            app_data: app_data
+                .iter()
+                .map(|(key, value)| crate::mcs::AppData {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            raw_data: Some(Bytes::from_static(b"ciphertext")),
+            ..Default::default()
+        }
+        .encode_to_vec()
+        .into()
+    }
+
+    #[test]
+    fn short_crypto_params_are_undecodable() {
+        for app_data in [
+            [("crypto-key", "dh"), ("encryption", "salt=AAAA")],
+            [("crypto-key", "dh=AAAA"), ("encryption", "salt")],
+            [("crypto-key", ""), ("encryption", "")],
+        ] {
+            match DataMessage::decode(&[], stanza(&app_data)) {
+                Err(Error::Undecodable(message)) => {
+                    assert!(matches!(message.error, Error::MissingCryptoMetadata(_)));
+                    assert_eq!(
+                        message.persistent_id.as_deref(),
+                        Some("0:1700000000000000%7031b2e6f9fd7ecd")
+                    );
+                }
+                other => panic!("expected an undecodable message, got {:?}", other.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn plain_messages_skip_decryption() {
+        let message = DataMessage::decode(&[], stanza(&[("score", "850")])).unwrap();
+        assert!(message.key.is_none());
+        assert_eq!(message.body, Bytes::from_static(b"ciphertext"));
+        assert_eq!(message.app_data["score"], "850");
+    }
+}
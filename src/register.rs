@@ -1,4 +1,4 @@
-use crate::{fcm, firebase, gcm, Error};
+use crate::{fcm, firebase, gcm, DeviceProfile, Error, FirebaseConfig, Http};
 use serde::Deserialize;
 use serde::Serialize;
 use uuid::Uuid;
@@ -8,49 +8,415 @@ pub struct Registration {
     pub fcm_token: String,
     pub gcm: gcm::Session,
     pub keys: fcm::WebPushKeys,
+
+    /// The app ID the GCM token was requested for, needed to unregister it. Registrations stored
+    /// by older versions don't have it.
+    #[serde(default)]
+    pub gcm_app_id: Option<String>,
+
+    /// The Firebase installation the FCM token was obtained with, whose auth token is renewed as
+    /// needed. Registrations stored by older versions don't have it.
+    #[serde(default)]
+    pub installation: Option<firebase::Installation>,
+}
+
+impl std::fmt::Debug for Registration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Registration")
+            .field("fcm_token", &crate::REDACTED)
+            .field("gcm", &self.gcm)
+            .field("keys", &self.keys)
+            .field("gcm_app_id", &self.gcm_app_id)
+            .field("installation", &self.installation)
+            .finish()
+    }
+}
+
+impl Registration {
+    /// check that the registration is complete and its keys are consistent, e.g. after loading
+    /// it from storage
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.fcm_token.is_empty() {
+            return Err(Error::InvalidRegistration("FCM token is empty"));
+        }
+
+        self.gcm.validate()?;
+        self.keys.validate()
+    }
+
+    /// check in with the registration's session, ready to open a connection
+    ///
+    /// If the checkin hands out a new android id, security token or settings digest, the
+    /// registration takes the new session and `on_updated` is called with it, so it can be saved
+    /// before the connection is opened. An error from `on_updated` is returned as is, in place of
+    /// the session, and the registration keeps its previous session so the next checkin calls
+    /// `on_updated` again.
+    pub async fn checkin<'a, E: From<Error>>(
+        &mut self,
+        http: impl Into<Http<'a>>,
+        on_updated: impl FnOnce(&Registration) -> Result<(), E>,
+    ) -> Result<gcm::CheckedSession, E> {
+        let session = self.gcm.checkin(http).await?;
+        if session.changed(&self.gcm) {
+            log::debug!("Checkin updated the session");
+            let previous = std::mem::replace(&mut self.gcm, gcm::Session::clone(&session));
+            if let Err(e) = on_updated(self) {
+                self.gcm = previous;
+                return Err(e);
+            }
+        }
+
+        Ok(session)
+    }
+
+    /// get a fresh FCM token for the existing device and keys, returning whether it changed
+    ///
+    /// Unlike calling [`register`] again, this keeps the android id and the web push keys, so
+    /// nothing else needs updating unless the token changed.
+    ///
+    /// Registrations stored by older versions have no `gcm_app_id`. They get a new GCM
+    /// registration under a new app ID, leaving the old one behind since it can't be
+    /// unregistered without its app ID.
+    pub async fn refresh<'a>(
+        &mut self,
+        http: impl Into<Http<'a>>,
+        firebase: &FirebaseConfig,
+    ) -> Result<bool, Error> {
+        let http = http.into();
+        let gcm_app_id = self.gcm_app_id.clone().unwrap_or_else(new_gcm_app_id);
+
+        log::debug!("Registering to GCM");
+        let gcm_token = self.gcm.request_token(&http, &gcm_app_id).await?;
+
+        log::debug!("Getting Firebase installation token");
+        let firebase_installation = self.current_installation(&http, firebase).await?;
+
+        log::debug!("Calling FCM register");
+        let fcm_register_result = fcm::Registration::request(
+            &http,
+            firebase,
+            &firebase_installation.auth_token.value,
+            &gcm_token,
+            self.keys.clone(),
+        )
+        .await?;
+
+        let changed = fcm_register_result.fcm_token != self.fcm_token;
+        self.fcm_token = fcm_register_result.fcm_token;
+        self.gcm_app_id = Some(gcm_app_id);
+        self.installation = Some(firebase_installation);
+        Ok(changed)
+    }
+
+    /// the stored installation with an unexpired auth token, or a new installation for
+    /// registrations stored by older versions, which then replaces the one the FCM registration
+    /// was created with
+    async fn current_installation(
+        &self,
+        http: &Http<'_>,
+        firebase: &FirebaseConfig,
+    ) -> Result<firebase::Installation, Error> {
+        match self.installation.clone() {
+            Some(mut installation) => {
+                installation.auth_token(http, firebase).await?;
+                Ok(installation)
+            }
+            None => firebase::Installation::create(http, firebase).await,
+        }
+    }
+}
+
+/// Which step [`RegistrationBuilder`] takes next
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegistrationStage {
+    /// Check in to GCM to get an android id
+    Checkin,
+    /// Request a GCM token for the android id
+    GcmToken,
+    /// Create the Firebase installation
+    Installation,
+    /// Register the web push keys with FCM to get the FCM token
+    FcmRegistration,
+}
+
+/// Runs the steps of [`register`] one at a time, keeping what each one produced.
+///
+/// Serialize it between steps to pick up where a failed attempt left off, e.g. after fixing the
+/// API key permissions, instead of redoing the checkin and burning another android id. Every
+/// step is skipped if its result is already there. The `Debug` output leaves out the tokens.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct RegistrationBuilder {
+    #[serde(default)]
+    device: DeviceProfile,
+    gcm: Option<gcm::Session>,
+    gcm_app_id: Option<String>,
+    gcm_token: Option<String>,
+    installation: Option<firebase::Installation>,
+    keys: Option<fcm::WebPushKeys>,
+
+    #[serde(skip)]
+    checkin_result: Option<gcm::CheckinResult>,
+}
+
+impl std::fmt::Debug for RegistrationBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistrationBuilder")
+            .field("device", &self.device)
+            .field("gcm", &self.gcm)
+            .field("gcm_app_id", &self.gcm_app_id)
+            .field(
+                "gcm_token",
+                &self.gcm_token.as_ref().map(|_| crate::REDACTED),
+            )
+            .field("installation", &self.installation)
+            .field("keys", &self.keys)
+            .field("checkin_result", &self.checkin_result)
+            .finish()
+    }
+}
+
+impl RegistrationBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the Chrome install to check in as, see [`DeviceProfile`]. Has no effect after the checkin.
+    pub fn with_device_profile(mut self, device: DeviceProfile) -> Self {
+        self.device = device;
+        self
+    }
+
+    /// the step still to be taken
+    pub fn stage(&self) -> RegistrationStage {
+        if self.gcm.is_none() {
+            RegistrationStage::Checkin
+        } else if self.gcm_token.is_none() || self.gcm_app_id.is_none() {
+            RegistrationStage::GcmToken
+        } else if self.installation.is_none() {
+            RegistrationStage::Installation
+        } else {
+            RegistrationStage::FcmRegistration
+        }
+    }
+
+    pub fn gcm(&self) -> Option<&gcm::Session> {
+        self.gcm.as_ref()
+    }
+
+    pub fn gcm_token(&self) -> Option<&str> {
+        self.gcm_token.as_deref()
+    }
+
+    pub fn installation(&self) -> Option<&firebase::Installation> {
+        self.installation.as_ref()
+    }
+
+    /// the settings and server time reported by the checkin, unless it was taken before the
+    /// builder was saved
+    pub fn checkin_result(&self) -> Option<&gcm::CheckinResult> {
+        self.checkin_result.as_ref()
+    }
+
+    /// check in to GCM, unless already done
+    pub async fn checkin<'a>(&mut self, http: impl Into<Http<'a>>) -> Result<&gcm::Session, Error> {
+        if self.gcm.is_none() {
+            log::debug!("Checking in to GCM");
+            let device = self.device.clone();
+            let (session, result) = gcm::Session::create_with_device(http, device)
+                .await?
+                .into_parts();
+            self.gcm = Some(session);
+            self.checkin_result = Some(result);
+        }
+
+        Ok(self.gcm.as_ref().expect("session was just set"))
+    }
+
+    /// request the GCM token, unless already done. Needs [`RegistrationBuilder::checkin`] first.
+    pub async fn request_gcm_token<'a>(
+        &mut self,
+        http: impl Into<Http<'a>>,
+    ) -> Result<&str, Error> {
+        if self.gcm_token.is_none() || self.gcm_app_id.is_none() {
+            let gcm = self
+                .gcm
+                .as_ref()
+                .ok_or(Error::RegistrationIncomplete(RegistrationStage::Checkin))?;
+            let gcm_app_id = self.gcm_app_id.get_or_insert_with(new_gcm_app_id);
+
+            log::debug!("Registering to GCM");
+            self.gcm_token = Some(gcm.request_token(http, gcm_app_id).await?);
+        }
+
+        Ok(self.gcm_token.as_deref().expect("token was just set"))
+    }
+
+    /// create the Firebase installation, unless already done
+    pub async fn create_installation<'a>(
+        &mut self,
+        http: impl Into<Http<'a>>,
+        firebase: &FirebaseConfig,
+    ) -> Result<&firebase::Installation, Error> {
+        if self.installation.is_none() {
+            log::debug!("Getting Firebase installation token");
+            self.installation = Some(firebase::Installation::create(http, firebase).await?);
+        }
+
+        Ok(self
+            .installation
+            .as_ref()
+            .expect("installation was just set"))
+    }
+
+    /// register with FCM, which needs all the earlier steps, and hand out the registration
+    ///
+    /// The web push keys are created on the first attempt and reused by later ones. The
+    /// installation's auth token is renewed first if it is about to expire.
+    pub async fn register_fcm<'a>(
+        &mut self,
+        http: impl Into<Http<'a>>,
+        firebase: &FirebaseConfig,
+    ) -> Result<Registration, Error> {
+        let http = http.into();
+        let stage = self.stage();
+        let (Some(gcm), Some(gcm_token), Some(gcm_app_id), Some(installation)) = (
+            &self.gcm,
+            &self.gcm_token,
+            &self.gcm_app_id,
+            &mut self.installation,
+        ) else {
+            return Err(Error::RegistrationIncomplete(stage));
+        };
+
+        let keys = match &self.keys {
+            Some(keys) => keys.clone(),
+            None => {
+                let keys = fcm::WebPushKeys::new().map_err(|e| Error::Crypto("key creation", e))?;
+                self.keys.insert(keys).clone()
+            }
+        };
+
+        log::debug!("Calling FCM register");
+        let auth_token = installation.auth_token(&http, firebase).await?;
+        let fcm_register_result =
+            fcm::Registration::request(&http, firebase, auth_token, gcm_token, keys).await?;
+
+        log::debug!("Registration complete");
+
+        Ok(Registration {
+            gcm: gcm.clone(),
+            fcm_token: fcm_register_result.fcm_token,
+            keys: fcm_register_result.keys,
+            gcm_app_id: Some(gcm_app_id.clone()),
+            installation: Some(installation.clone()),
+        })
+    }
+
+    /// take every step still to be taken
+    pub async fn finish<'a>(
+        &mut self,
+        http: impl Into<Http<'a>>,
+        firebase: &FirebaseConfig,
+    ) -> Result<Registration, Error> {
+        let http = http.into();
+        self.checkin(&http).await?;
+        self.request_gcm_token(&http).await?;
+        self.create_installation(&http, firebase).await?;
+        self.register_fcm(&http, firebase).await
+    }
 }
 
-pub async fn register(
-    http: &reqwest::Client,
-    firebase_app_id: &str,
-    firebase_project_id: &str,
-    firebase_api_key: &str,
-    vapid_key: Option<&str>,
+/// register with GCM and FCM in one go, see [`RegistrationBuilder`] to resume a failed attempt
+pub async fn register<'a>(
+    http: impl Into<Http<'a>>,
+    firebase: &FirebaseConfig,
 ) -> Result<Registration, Error> {
-    log::debug!("Checking in to GCM");
-    let gcm_session = gcm::Session::create(http).await?;
+    RegistrationBuilder::new().finish(http, firebase).await
+}
+
+/// What [`unregister`] managed to tear down. Every step is attempted, even if an earlier one
+/// failed.
+#[derive(Debug)]
+pub struct Unregistration {
+    /// Deleting the FCM registration, after which the token no longer receives messages
+    pub fcm: Result<(), Error>,
+
+    /// Deleting the GCM registration the FCM one was built on
+    pub gcm: Result<(), Error>,
+
+    /// Deleting the Firebase installation, `None` if it wasn't asked for or no auth token could
+    /// be obtained for it
+    pub installation: Option<Result<(), Error>>,
+}
+
+impl Unregistration {
+    /// whether every step succeeded
+    pub fn is_complete(&self) -> bool {
+        self.fcm.is_ok() && self.gcm.is_ok() && !matches!(self.installation, Some(Err(_)))
+    }
+}
+
+/// tear down what [`register`] set up, e.g. when the user signs out, and delete the Firebase
+/// installation too if `delete_installation` is set
+///
+/// Registrations stored by older versions lack the installation that authorized the FCM
+/// registration and the GCM app ID, so those steps fail with [`Error::InvalidRegistration`].
+pub async fn unregister<'a>(
+    http: impl Into<Http<'a>>,
+    registration: &Registration,
+    firebase: &FirebaseConfig,
+    delete_installation: bool,
+) -> Unregistration {
+    let http = http.into();
+
+    let (fcm, installation) = match registration.installation.clone() {
+        None => (
+            Err(Error::InvalidRegistration("has no Firebase installation")),
+            None,
+        ),
+        Some(mut installation) => {
+            log::debug!("Getting Firebase installation token");
+            match installation.auth_token(&http, firebase).await {
+                Err(e) => (Err(e), None),
+                Ok(_) => {
+                    log::debug!("Calling FCM unregister");
+                    let fcm = fcm::Registration::delete(
+                        &http,
+                        firebase,
+                        &installation.auth_token.value,
+                        &registration.fcm_token,
+                    )
+                    .await;
+
+                    let deleted = match delete_installation {
+                        true => {
+                            log::debug!("Deleting Firebase installation");
+                            Some(installation.delete(&http, firebase).await)
+                        }
+                        false => None,
+                    };
+                    (fcm, deleted)
+                }
+            }
+        }
+    };
+
+    let gcm = match &registration.gcm_app_id {
+        None => Err(Error::InvalidRegistration("has no GCM app ID")),
+        Some(app_id) => {
+            log::debug!("Unregistering from GCM");
+            registration.gcm.delete_token(&http, app_id).await
+        }
+    };
+
+    Unregistration {
+        fcm,
+        gcm,
+        installation,
+    }
+}
 
+fn new_gcm_app_id() -> String {
     let id = Uuid::new_v4();
-    let gcm_app_id = format!("wp:receiver.push.com#{id}");
-
-    log::debug!("Registering to GCM");
-    let gcm_token = gcm_session.request_token(&gcm_app_id).await?;
-
-    log::debug!("Getting Firebase installation token");
-    let firebase_installation_token = firebase::InstallationAuthToken::request(
-        http,
-        firebase_app_id,
-        firebase_project_id,
-        firebase_api_key,
-    )
-    .await?;
-
-    log::debug!("Calling FCM register");
-    let fcm_register_result = fcm::Registration::request(
-        http,
-        firebase_project_id,
-        firebase_api_key,
-        vapid_key,
-        &firebase_installation_token.value,
-        &gcm_token,
-    )
-    .await?;
-
-    log::debug!("Registration complete");
-
-    Ok(Registration {
-        gcm: gcm_session,
-        fcm_token: fcm_register_result.fcm_token,
-        keys: fcm_register_result.keys,
-    })
+    format!("wp:receiver.push.com#{id}")
 }
@@ -0,0 +1,283 @@
+use crate::{Error, Registration};
+use ring::aead;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use zeroize::Zeroizing;
+
+// Envelope layout, all integers big endian:
+//   magic "FCMSEAL" | version u8 | kdf u8 | iterations u32 | salt [16] | nonce [12] | ciphertext
+// Everything before the ciphertext is authenticated along with it.
+const MAGIC: &[u8] = b"FCMSEAL";
+const VERSION: u8 = 1;
+const KDF_KEY: u8 = 0;
+const KDF_PASSPHRASE: u8 = 1;
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 4 + SALT_LEN + aead::NONCE_LEN;
+
+/// OWASP's recommendation for PBKDF2-HMAC-SHA256
+const PASSPHRASE_ITERATIONS: u32 = 600_000;
+
+const ERR_MALFORMED: Error = Error::Envelope("is malformed");
+
+/// Everything a listener needs to pick up where it left off after a restart
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ListenerState {
+    pub registration: Registration,
+
+    /// Persistent IDs of the messages received so far, to pass to `new_connection()`
+    pub received_persistent_ids: Vec<String>,
+}
+
+/// What the envelope encryption key is derived from
+pub enum SealingKey<'a> {
+    /// A user supplied passphrase, stretched with PBKDF2
+    Passphrase(&'a str),
+    /// A random 256-bit key, e.g. from the platform keychain
+    Key(&'a [u8; 32]),
+}
+
+impl SealingKey<'_> {
+    fn kdf(&self) -> u8 {
+        match self {
+            Self::Passphrase(_) => KDF_PASSPHRASE,
+            Self::Key(_) => KDF_KEY,
+        }
+    }
+
+    /// the PBKDF2 iterations, the only count `open` accepts so a tampered header can't make it
+    /// spin for hours
+    fn iterations(&self) -> u32 {
+        match self {
+            Self::Passphrase(_) => PASSPHRASE_ITERATIONS,
+            Self::Key(_) => 0,
+        }
+    }
+
+    fn derive(&self, iterations: u32, salt: &[u8]) -> Result<aead::LessSafeKey, Error> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        match self {
+            Self::Passphrase(passphrase) => {
+                let iterations = NonZeroU32::new(iterations).ok_or(ERR_MALFORMED)?;
+                ring::pbkdf2::derive(
+                    ring::pbkdf2::PBKDF2_HMAC_SHA256,
+                    iterations,
+                    salt,
+                    passphrase.as_bytes(),
+                    key.as_mut(),
+                );
+            }
+            Self::Key(secret) => {
+                ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, salt)
+                    .extract(secret.as_slice())
+                    .expand(&[MAGIC], ring::hkdf::HKDF_SHA256)
+                    .and_then(|okm| okm.fill(key.as_mut()))
+                    .expect("output length should match the digest length");
+            }
+        }
+
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key.as_ref())
+            .expect("key length should match the algorithm");
+        Ok(aead::LessSafeKey::new(key))
+    }
+}
+
+/// Encrypt the state into a self-describing envelope that can be written to disk
+pub fn seal(state: &ListenerState, key: &SealingKey) -> Result<Vec<u8>, Error> {
+    use rand::RngCore;
+
+    let iterations = key.iterations();
+
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    rand::rng().fill_bytes(&mut salt);
+    rand::rng().fill_bytes(&mut nonce);
+
+    let plaintext =
+        Zeroizing::new(serde_json::to_vec(state).expect("listener state should always serialize"));
+
+    let mut envelope = Vec::with_capacity(HEADER_LEN + plaintext.len() + aead::MAX_TAG_LEN);
+    envelope.extend_from_slice(MAGIC);
+    envelope.push(VERSION);
+    envelope.push(key.kdf());
+    envelope.extend_from_slice(&iterations.to_be_bytes());
+    envelope.extend_from_slice(&salt);
+    envelope.extend_from_slice(&nonce);
+
+    let mut ciphertext = Vec::with_capacity(plaintext.len() + aead::MAX_TAG_LEN);
+    ciphertext.extend_from_slice(&plaintext);
+    key.derive(iterations, &salt)?
+        .seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&envelope),
+            &mut ciphertext,
+        )
+        .map_err(|_| Error::Envelope("could not be encrypted"))?;
+
+    envelope.extend_from_slice(&ciphertext);
+    Ok(envelope)
+}
+
+/// Decrypt an envelope produced by [`seal`]
+pub fn open(envelope: &[u8], key: &SealingKey) -> Result<ListenerState, Error> {
+    if envelope.len() < HEADER_LEN || !envelope.starts_with(MAGIC) {
+        return Err(ERR_MALFORMED);
+    }
+
+    let (header, ciphertext) = envelope.split_at(HEADER_LEN);
+    let (version, rest) = header[MAGIC.len()..].split_at(1);
+    if version[0] != VERSION {
+        return Err(Error::Envelope("has an unsupported version"));
+    }
+
+    let (kdf, rest) = rest.split_at(1);
+    if kdf[0] != key.kdf() {
+        return Err(Error::Envelope("was sealed with a different kind of key"));
+    }
+
+    let (iterations, rest) = rest.split_at(4);
+    let iterations = u32::from_be_bytes(iterations.try_into().expect("slice is 4 bytes"));
+    if iterations != key.iterations() {
+        return Err(Error::Envelope("has an unsupported iteration count"));
+    }
+    let (salt, nonce) = rest.split_at(SALT_LEN);
+    let nonce = aead::Nonce::try_assume_unique_for_key(nonce).or(Err(ERR_MALFORMED))?;
+
+    let mut plaintext = Zeroizing::new(ciphertext.to_vec());
+    let plaintext = key
+        .derive(iterations, salt)?
+        .open_in_place(nonce, aead::Aad::from(header), &mut plaintext)
+        .map_err(|_| Error::Envelope("could not be decrypted, wrong key or corrupted data"))?;
+
+    serde_json::from_slice(plaintext).map_err(|e| Error::Json("sealed listener state", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gcm, DeviceProfile, WebPushKeys};
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn state(security_token: u64) -> ListenerState {
+        ListenerState {
+            registration: Registration {
+                fcm_token: "fE8aC1xXRR2:APA91bH-fcm-token".to_owned(),
+                gcm: gcm::Session {
+                    android_id: 5_123_456_789_012_345_678,
+                    security_token,
+                    device: DeviceProfile::default(),
+                    digest: None,
+                },
+                keys: WebPushKeys::new().unwrap(),
+                gcm_app_id: Some("wp:receiver.push.com#5f6ab9d4".to_owned()),
+                installation: None,
+            },
+            received_persistent_ids: vec!["0:1%7031b2e6f9fd7ecd".to_owned()],
+        }
+    }
+
+    fn assert_envelope_error(result: Result<ListenerState, Error>, expected: &str) {
+        match result {
+            Err(Error::Envelope(problem)) => assert_eq!(problem, expected),
+            other => panic!("expected {expected:?}, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let state = state(8_765_432_109_876_543_210);
+        for key in [
+            SealingKey::Passphrase("correct horse"),
+            SealingKey::Key(&KEY),
+        ] {
+            let sealed = seal(&state, &key).unwrap();
+            assert!(sealed.starts_with(b"FCMSEAL\x01"));
+            assert_eq!(sealed[MAGIC.len() + 1], key.kdf());
+
+            let opened = open(&sealed, &key).unwrap();
+            assert_eq!(
+                serde_json::to_string(&opened).unwrap(),
+                serde_json::to_string(&state).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let sealed = seal(&state(1), &SealingKey::Key(&KEY)).unwrap();
+
+        let result = open(&sealed, &SealingKey::Key(&[8; 32]));
+        assert_envelope_error(
+            result,
+            "could not be decrypted, wrong key or corrupted data",
+        );
+
+        let result = open(&sealed, &SealingKey::Passphrase("correct horse"));
+        assert_envelope_error(result, "was sealed with a different kind of key");
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let sealed = seal(&state(1), &SealingKey::Key(&KEY)).unwrap();
+
+        // the salt is part of the authenticated header
+        let mut tampered = sealed.clone();
+        tampered[HEADER_LEN - aead::NONCE_LEN - 1] ^= 1;
+        let result = open(&tampered, &SealingKey::Key(&KEY));
+        assert_envelope_error(
+            result,
+            "could not be decrypted, wrong key or corrupted data",
+        );
+
+        let mut tampered = sealed.clone();
+        tampered[HEADER_LEN + 1] ^= 1;
+        let result = open(&tampered, &SealingKey::Key(&KEY));
+        assert_envelope_error(
+            result,
+            "could not be decrypted, wrong key or corrupted data",
+        );
+
+        let mut tampered = sealed;
+        tampered[0] ^= 1;
+        let result = open(&tampered, &SealingKey::Key(&KEY));
+        assert_envelope_error(result, "is malformed");
+    }
+
+    #[test]
+    fn rejects_truncation() {
+        let sealed = seal(&state(1), &SealingKey::Key(&KEY)).unwrap();
+
+        let result = open(&sealed[..sealed.len() - 1], &SealingKey::Key(&KEY));
+        assert_envelope_error(
+            result,
+            "could not be decrypted, wrong key or corrupted data",
+        );
+
+        let result = open(&sealed[..HEADER_LEN - 1], &SealingKey::Key(&KEY));
+        assert_envelope_error(result, "is malformed");
+    }
+
+    #[test]
+    fn rejects_other_iteration_counts() {
+        let key = SealingKey::Passphrase("correct horse");
+        let mut sealed = seal(&state(1), &key).unwrap();
+
+        // would take hours to derive
+        let iterations = MAGIC.len() + 2;
+        sealed[iterations..iterations + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let result = open(&sealed, &key);
+        assert_envelope_error(result, "has an unsupported iteration count");
+    }
+
+    #[test]
+    fn reports_invalid_registrations() {
+        let sealed = seal(&state(0), &SealingKey::Key(&KEY)).unwrap();
+
+        match open(&sealed, &SealingKey::Key(&KEY)) {
+            Err(Error::Json(_, e)) => {
+                assert!(e.to_string().contains("security token is zero"), "{e}")
+            }
+            other => panic!("expected an invalid registration, got {:?}", other.err()),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use bytes::Bytes;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Sends the requests to Google's services.
+///
+/// Implemented for `reqwest::Client` with the `reqwest` feature, which is on by default.
+/// Implement it to use another HTTP client, or an in-memory fake in tests. Only failures to
+/// get a response are errors, error statuses are handled by the caller.
+pub trait HttpTransport: Send + Sync {
+    fn send(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Pin<Box<dyn Future<Output = Result<http::Response<Bytes>, TransportError>> + Send + '_>>;
+}
+
+/// A request could not be sent or its response could not be read
+#[derive(Debug)]
+pub struct TransportError {
+    kind: Kind,
+    source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    Permanent,
+    Retryable,
+    NotSent,
+}
+
+impl TransportError {
+    pub fn new(source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self {
+            kind: Kind::Permanent,
+            source: source.into(),
+        }
+    }
+
+    /// a failure worth trying again that may have happened after the server got the request,
+    /// such as a timeout
+    pub fn retryable(source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self {
+            kind: Kind::Retryable,
+            source: source.into(),
+        }
+    }
+
+    /// a failure before any of the request was sent, such as a refused connection, so even
+    /// calls that create something can be tried again
+    pub fn not_sent(source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self {
+            kind: Kind::NotSent,
+            source: source.into(),
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.kind != Kind::Permanent
+    }
+
+    pub fn is_not_sent(&self) -> bool {
+        self.kind == Kind::NotSent
+    }
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpTransport for reqwest::Client {
+    fn send(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Pin<Box<dyn Future<Output = Result<http::Response<Bytes>, TransportError>> + Send + '_>>
+    {
+        Box::pin(async move {
+            let request = reqwest::Request::try_from(request).map_err(TransportError::new)?;
+            let response = self.execute(request).await.map_err(from_reqwest)?;
+
+            let mut builder = http::Response::builder()
+                .status(response.status())
+                .version(response.version());
+            if let Some(headers) = builder.headers_mut() {
+                *headers = response.headers().clone();
+            }
+
+            let body = response.bytes().await.map_err(from_reqwest)?;
+            builder.body(body).map_err(TransportError::new)
+        })
+    }
+}
+
+/// drops the URL from the error, since some carry the FCM token
+#[cfg(feature = "reqwest")]
+fn from_reqwest(e: reqwest::Error) -> TransportError {
+    let e = e.without_url();
+    if e.is_connect() {
+        TransportError::not_sent(e)
+    } else if e.is_timeout() {
+        TransportError::retryable(e)
+    } else {
+        TransportError::new(e)
+    }
+}
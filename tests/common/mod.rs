@@ -0,0 +1,249 @@
+//! An in-memory `HttpTransport` that answers with queued responses and records the requests,
+//! along with the responses Google's services give.
+
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use fcm_push_listener::{FirebaseConfig, Http, HttpTransport, RetryPolicy, TransportError};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const CHECKIN_URL: &str = "https://android.clients.google.com/checkin";
+pub const REGISTER_URL: &str = "https://android.clients.google.com/c2dm/register3";
+pub const INSTALLATIONS_URL: &str =
+    "https://firebaseinstallations.googleapis.com/v1/projects/myapp-1234567890123/installations";
+pub const FCM_URL: &str =
+    "https://fcmregistrations.googleapis.com/v1/projects/myapp-1234567890123/registrations";
+
+pub const ANDROID_ID: u64 = 5_123_456_789_012_345_678;
+pub const SECURITY_TOKEN: u64 = 8_765_432_109_876_543_210;
+pub const GCM_TOKEN: &str = "cKp2o1mWRuo:APA91bG-gcm-token";
+pub const FID: &str = "cS8c2BvfRT-gVu3VXyQ6yx";
+pub const FCM_TOKEN: &str = "fE8aC1xXRR2:APA91bH-fcm-token";
+
+/// A request as the fake received it
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    pub method: http::Method,
+    pub uri: String,
+    pub headers: http::HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Recorded {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)?.to_str().ok()
+    }
+
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).unwrap()
+    }
+
+    /// the `key=value` pairs of a form body
+    pub fn form(&self) -> Vec<(String, String)> {
+        serde_urlencoded::from_bytes(&self.body).unwrap()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+#[derive(Default)]
+pub struct FakeTransport {
+    responses: Mutex<VecDeque<Result<http::Response<Bytes>, TransportError>>>,
+    requests: Mutex<Vec<Recorded>>,
+}
+
+impl FakeTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// queue the response to the next request
+    pub fn respond(&self, response: http::Response<Bytes>) -> &Self {
+        self.responses.lock().unwrap().push_back(Ok(response));
+        self
+    }
+
+    /// queue a failure to get a response for the next request
+    pub fn fail(&self, error: TransportError) -> &Self {
+        self.responses.lock().unwrap().push_back(Err(error));
+        self
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn uris(&self) -> Vec<String> {
+        self.requests().into_iter().map(|r| r.uri).collect()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.responses.lock().unwrap().len()
+    }
+}
+
+impl HttpTransport for FakeTransport {
+    fn send(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Pin<Box<dyn Future<Output = Result<http::Response<Bytes>, TransportError>> + Send + '_>>
+    {
+        let (parts, body) = request.into_parts();
+        let uri = parts.uri.to_string();
+        self.requests.lock().unwrap().push(Recorded {
+            method: parts.method,
+            uri: uri.clone(),
+            headers: parts.headers,
+            body,
+        });
+
+        let response = self.responses.lock().unwrap().pop_front();
+        Box::pin(async move { response.unwrap_or_else(|| panic!("unexpected request to {uri}")) })
+    }
+}
+
+/// retries without waiting, so tests don't sleep through the backoff
+pub fn http(transport: &FakeTransport) -> Http<'_> {
+    Http::new(transport).with_retry_policy(
+        RetryPolicy::default()
+            .with_initial_backoff(Duration::from_millis(1))
+            .with_max_backoff(Duration::from_millis(10)),
+    )
+}
+
+pub fn firebase() -> FirebaseConfig {
+    FirebaseConfig::builder()
+        .with_app_id("1:1001234567890:web:2665128ba997ffab830a24")
+        .with_project_id("myapp-1234567890123")
+        .with_api_key("AIzaSyB-fake-api-key")
+        .build()
+        .unwrap()
+}
+
+pub fn response(status: u16, body: impl Into<Bytes>) -> http::Response<Bytes> {
+    http::Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap()
+}
+
+pub fn json(status: u16, body: serde_json::Value) -> http::Response<Bytes> {
+    let mut response = response(status, serde_json::to_vec(&body).unwrap());
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+/// The parts of `AndroidCheckinResponse` from checkin.proto that the checkin reads
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AndroidCheckinResponse {
+    #[prost(bool, required, tag = "1")]
+    pub stats_ok: bool,
+    #[prost(int64, optional, tag = "3")]
+    pub time_msec: Option<i64>,
+    #[prost(string, optional, tag = "4")]
+    pub digest: Option<String>,
+    #[prost(message, repeated, tag = "5")]
+    pub setting: Vec<GservicesSetting>,
+    #[prost(bool, optional, tag = "9")]
+    pub settings_diff: Option<bool>,
+    #[prost(string, repeated, tag = "10")]
+    pub delete_setting: Vec<String>,
+    #[prost(fixed64, optional, tag = "7")]
+    pub android_id: Option<u64>,
+    #[prost(fixed64, optional, tag = "8")]
+    pub security_token: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GservicesSetting {
+    #[prost(bytes = "vec", required, tag = "1")]
+    pub name: Vec<u8>,
+    #[prost(bytes = "vec", required, tag = "2")]
+    pub value: Vec<u8>,
+}
+
+pub fn checkin_response(android_id: u64, security_token: u64) -> AndroidCheckinResponse {
+    AndroidCheckinResponse {
+        stats_ok: true,
+        android_id: Some(android_id),
+        security_token: Some(security_token),
+        ..Default::default()
+    }
+}
+
+pub fn checkin(response: AndroidCheckinResponse) -> http::Response<Bytes> {
+    self::response(200, prost::Message::encode_to_vec(&response))
+}
+
+pub fn register3(token: &str) -> http::Response<Bytes> {
+    response(200, format!("token={token}"))
+}
+
+pub fn installation() -> http::Response<Bytes> {
+    json(
+        200,
+        serde_json::json!({
+            "name": format!("projects/1001234567890/installations/{FID}"),
+            "fid": FID,
+            "refreshToken": "2_refresh-token",
+            "authToken": {
+                "token": "eyJhbGciOi.auth-token",
+                "expiresIn": "604800s",
+            },
+        }),
+    )
+}
+
+pub fn fcm_registration(token: &str) -> http::Response<Bytes> {
+    json(
+        200,
+        serde_json::json!({
+            "name": format!("projects/myapp-1234567890123/registrations/{token}"),
+            "token": token,
+            "web": {
+                "endpoint": format!("https://fcm.googleapis.com/fcm/send/{GCM_TOKEN}"),
+            },
+        }),
+    )
+}
+
+/// Google's error response, e.g. for an API key that may not call the FCM Registration API
+pub fn permission_denied() -> http::Response<Bytes> {
+    json(
+        403,
+        serde_json::json!({
+            "error": {
+                "code": 403,
+                "message": "Requests to this API fcmregistrations.googleapis.com method google.firebase.fcm.registration.v1.RegistrationService.CreateRegistration are blocked.",
+                "status": "PERMISSION_DENIED",
+                "details": [{
+                    "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                    "reason": "API_KEY_SERVICE_BLOCKED",
+                    "domain": "googleapis.com",
+                    "metadata": {
+                        "service": "fcmregistrations.googleapis.com",
+                        "consumer": "projects/1001234567890",
+                    },
+                }],
+            },
+        }),
+    )
+}
+
+/// queue the responses of a whole registration
+pub fn respond_to_registration(transport: &FakeTransport) {
+    transport
+        .respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)))
+        .respond(register3(GCM_TOKEN))
+        .respond(installation())
+        .respond(fcm_registration(FCM_TOKEN));
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use common::*;
+use fcm_push_listener::{ApiError, Error, Installation};
+use std::error::Error as _;
+
+#[tokio::test]
+async fn maps_google_errors() {
+    let transport = FakeTransport::new();
+    transport
+        .respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)))
+        .respond(register3(GCM_TOKEN))
+        .respond(installation())
+        .respond(permission_denied());
+
+    let error = fcm_push_listener::register(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+
+    let source = error.source().expect("API errors should have a source");
+    let api_error = source.downcast_ref::<ApiError>().unwrap();
+    assert_eq!(api_error.api, "FCM Registration");
+    assert_eq!(api_error.http_status, http::StatusCode::FORBIDDEN);
+    assert_eq!(api_error.code, Some(403));
+    assert_eq!(api_error.status.as_deref(), Some("PERMISSION_DENIED"));
+    assert!(api_error
+        .message
+        .as_deref()
+        .unwrap()
+        .contains("are blocked"));
+    assert!(api_error.is_rejection());
+    assert!(!api_error.is_retryable());
+
+    let detail = &api_error.details[0];
+    assert_eq!(detail.type_url, "type.googleapis.com/google.rpc.ErrorInfo");
+    assert_eq!(detail.reason.as_deref(), Some("API_KEY_SERVICE_BLOCKED"));
+    assert_eq!(
+        detail.metadata["service"],
+        "fcmregistrations.googleapis.com"
+    );
+
+    assert_eq!(
+        error.to_string(),
+        "FCM Registration API rejected request with 403 Forbidden PERMISSION_DENIED: \
+         Requests to this API fcmregistrations.googleapis.com method \
+         google.firebase.fcm.registration.v1.RegistrationService.CreateRegistration are blocked."
+    );
+}
+
+#[tokio::test]
+async fn keeps_text_of_other_errors() {
+    let transport = FakeTransport::new();
+    transport.respond(response(404, "Not Found\n"));
+
+    let error = Installation::create(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+    let Error::Api(error) = error else {
+        panic!("expected an API error, got {error:?}");
+    };
+    assert_eq!(error.message.as_deref(), Some("Not Found"));
+    assert_eq!(error.code, None);
+    assert_eq!(error.status, None);
+    assert!(error.is_rejection());
+}
+
+#[tokio::test]
+async fn server_errors_are_failures() {
+    let transport = FakeTransport::new();
+    transport.respond(json(
+        500,
+        serde_json::json!({
+            "error": {"code": 500, "message": "Internal error encountered.", "status": "INTERNAL"}
+        }),
+    ));
+
+    let error = Installation::create(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+    let Error::Api(error) = error else {
+        panic!("expected an API error, got {error:?}");
+    };
+    assert!(!error.is_rejection());
+    assert!(error.is_retryable());
+    assert_eq!(error.code, Some(500));
+}
+
+#[tokio::test]
+async fn reports_unexpected_responses() {
+    let transport = FakeTransport::new();
+    transport.respond(json(200, serde_json::json!({"fid": FID})));
+
+    let error = Installation::create(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, Error::Response("Firebase installation", _)),
+        "{error:?}"
+    );
+    assert!(error.source().is_some());
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn keeps_tokens_out_of_transport_errors() {
+    use fcm_push_listener::HttpTransport;
+
+    // nothing listens on port 1, so the connection is refused
+    let request = http::Request::delete(format!("http://127.0.0.1:1/registrations/{FCM_TOKEN}"))
+        .body(Vec::new())
+        .unwrap();
+    let error = reqwest::Client::new().send(request).await.unwrap_err();
+
+    assert!(error.is_not_sent());
+    assert!(
+        !format!("{error} {error:?}").contains(FCM_TOKEN),
+        "{error:?}"
+    );
+}
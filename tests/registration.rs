@@ -0,0 +1,481 @@
+mod common;
+
+use common::*;
+use fcm_push_listener::{Error, RegistrationBuilder, RegistrationStage, Session};
+
+#[tokio::test]
+async fn registers_in_four_steps() {
+    let transport = FakeTransport::new();
+    respond_to_registration(&transport);
+
+    let registration = fcm_push_listener::register(&http(&transport), &firebase())
+        .await
+        .unwrap();
+
+    assert_eq!(registration.gcm.android_id as u64, ANDROID_ID);
+    assert_eq!(registration.gcm.security_token, SECURITY_TOKEN);
+    assert_eq!(registration.fcm_token, FCM_TOKEN);
+    assert_eq!(registration.installation.as_ref().unwrap().fid, FID);
+    registration.validate().unwrap();
+
+    let requests = transport.requests();
+    let uris: Vec<_> = requests.iter().map(|r| r.uri.as_str()).collect();
+    assert_eq!(
+        uris,
+        [CHECKIN_URL, REGISTER_URL, INSTALLATIONS_URL, FCM_URL]
+    );
+
+    // The GCM token is requested for the android id the checkin handed out
+    let register3 = &requests[1];
+    assert_eq!(
+        register3.header("authorization"),
+        Some(format!("AidLogin {ANDROID_ID}:{SECURITY_TOKEN}").as_str())
+    );
+    let form = register3.form();
+    let field = |name: &str| {
+        form.iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    };
+    assert_eq!(field("device"), Some(ANDROID_ID.to_string().as_str()));
+    assert_eq!(field("X-subtype"), registration.gcm_app_id.as_deref());
+    assert!(field("sender").is_some());
+
+    let installation = &requests[2];
+    assert_eq!(
+        installation.header("x-goog-api-key"),
+        Some("AIzaSyB-fake-api-key")
+    );
+    assert_eq!(
+        installation.json()["appId"],
+        "1:1001234567890:web:2665128ba997ffab830a24"
+    );
+
+    // FCM is called with the installation's auth token and the GCM token as the endpoint
+    let fcm = &requests[3];
+    assert_eq!(fcm.method, http::Method::POST);
+    assert_eq!(
+        fcm.header("x-goog-firebase-installations-auth"),
+        Some("eyJhbGciOi.auth-token")
+    );
+    let web = &fcm.json()["web"];
+    assert_eq!(
+        web["endpoint"],
+        format!("https://fcm.googleapis.com/fcm/send/{GCM_TOKEN}")
+    );
+    assert!(web["p256dh"].as_str().is_some_and(|key| !key.is_empty()));
+}
+
+#[tokio::test]
+async fn checkin_reports_server_time_and_settings() {
+    let transport = FakeTransport::new();
+    transport.respond(checkin(AndroidCheckinResponse {
+        time_msec: Some(1_700_000_000_000),
+        digest: Some("1-da39a3ee5e6b4b0d3255bfef95601890afd80709".to_owned()),
+        setting: vec![
+            GservicesSetting {
+                name: b"gcm_secure_port".to_vec(),
+                value: b"5228".to_vec(),
+            },
+            GservicesSetting {
+                name: b"gcm_secure_port".to_vec(),
+                value: b"443".to_vec(),
+            },
+            GservicesSetting {
+                name: b"opaque".to_vec(),
+                value: vec![0xff, 0x00, 0xfe],
+            },
+        ],
+        ..checkin_response(ANDROID_ID, SECURITY_TOKEN)
+    }));
+
+    let checked = Session::create_with_device(&http(&transport), Default::default())
+        .await
+        .unwrap();
+    let result = checked.result();
+    assert_eq!(
+        result.server_time,
+        Some(std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_000))
+    );
+    assert!(result.clock_skew_millis().is_some());
+
+    // Settings keep their bytes, order and repeated names
+    let settings: Vec<_> = result
+        .settings
+        .iter()
+        .map(|s| (s.name.as_slice(), s.value.as_slice()))
+        .collect();
+    assert_eq!(
+        settings,
+        [
+            (&b"gcm_secure_port"[..], &b"5228"[..]),
+            (b"gcm_secure_port", b"443"),
+            (b"opaque", &[0xff, 0x00, 0xfe]),
+        ]
+    );
+
+    let (session, _) = checked.into_parts();
+    assert_eq!(
+        session.digest.as_deref(),
+        Some("1-da39a3ee5e6b4b0d3255bfef95601890afd80709")
+    );
+
+    // A later checkin sends back the android id, security token and digest
+    transport.respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)));
+    let checked = session.checkin(&http(&transport)).await.unwrap();
+    assert!(!checked.changed(&session));
+
+    let requests = transport.requests();
+    let request: CheckinRequest = prost::Message::decode(requests[1].body.as_slice()).unwrap();
+    assert_eq!(request.id, Some(ANDROID_ID as i64));
+    assert_eq!(request.security_token, Some(SECURITY_TOKEN));
+    assert_eq!(
+        request.digest.as_deref(),
+        Some("1-da39a3ee5e6b4b0d3255bfef95601890afd80709")
+    );
+    assert_eq!(
+        requests[1].header("content-type"),
+        Some("application/x-protobuf")
+    );
+}
+
+/// The parts of `AndroidCheckinRequest` from checkin.proto that identify the device
+#[derive(Clone, PartialEq, prost::Message)]
+struct CheckinRequest {
+    #[prost(string, optional, tag = "3")]
+    digest: Option<String>,
+    #[prost(fixed64, optional, tag = "13")]
+    security_token: Option<u64>,
+    #[prost(int64, optional, tag = "2")]
+    id: Option<i64>,
+}
+
+#[tokio::test]
+async fn checkin_without_credentials_fails() {
+    let transport = FakeTransport::new();
+    transport.respond(checkin(AndroidCheckinResponse {
+        stats_ok: true,
+        ..Default::default()
+    }));
+
+    let error = Session::create(&http(&transport)).await.unwrap_err();
+    assert!(
+        matches!(
+            error,
+            Error::DependencyFailure(_, "response is missing android id")
+        ),
+        "{error:?}"
+    );
+}
+
+#[tokio::test]
+async fn register3_errors_are_rejections() {
+    let transport = FakeTransport::new();
+    transport
+        .respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)))
+        .respond(response(200, "Error=PHONE_REGISTRATION_ERROR"));
+
+    let error = fcm_push_listener::register(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+
+    let Error::Api(error) = error else {
+        panic!("expected an API error, got {error:?}");
+    };
+    assert_eq!(error.status.as_deref(), Some("PHONE_REGISTRATION_ERROR"));
+    assert!(error.is_rejection());
+    assert!(!error.is_retryable());
+}
+
+#[tokio::test]
+async fn builder_resumes_after_failed_step() {
+    let transport = FakeTransport::new();
+    transport
+        .respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)))
+        .respond(register3(GCM_TOKEN))
+        .respond(installation())
+        .respond(permission_denied());
+
+    let mut builder = RegistrationBuilder::new();
+    let error = builder
+        .finish(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Api(_)), "{error:?}");
+    assert_eq!(builder.stage(), RegistrationStage::FcmRegistration);
+    assert!(builder.checkin_result().is_some());
+
+    // Pick up from storage, only the FCM registration is left
+    let saved = serde_json::to_string(&builder).unwrap();
+    let mut builder: RegistrationBuilder = serde_json::from_str(&saved).unwrap();
+    assert_eq!(builder.stage(), RegistrationStage::FcmRegistration);
+
+    let transport = FakeTransport::new();
+    transport.respond(fcm_registration(FCM_TOKEN));
+    let registration = builder
+        .finish(&http(&transport), &firebase())
+        .await
+        .unwrap();
+
+    assert_eq!(transport.uris(), [FCM_URL]);
+    assert_eq!(registration.gcm.android_id as u64, ANDROID_ID);
+    assert_eq!(registration.fcm_token, FCM_TOKEN);
+}
+
+#[tokio::test]
+async fn builder_steps_need_earlier_ones() {
+    let transport = FakeTransport::new();
+    let mut builder = RegistrationBuilder::new();
+
+    let error = builder
+        .request_gcm_token(&http(&transport))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        Error::RegistrationIncomplete(RegistrationStage::Checkin)
+    ));
+
+    let error = builder
+        .register_fcm(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        Error::RegistrationIncomplete(RegistrationStage::Checkin)
+    ));
+    assert!(transport.requests().is_empty());
+}
+
+#[tokio::test]
+async fn unregisters_what_was_registered() {
+    let transport = FakeTransport::new();
+    respond_to_registration(&transport);
+    let registration = fcm_push_listener::register(&http(&transport), &firebase())
+        .await
+        .unwrap();
+
+    let transport = FakeTransport::new();
+    transport
+        .respond(json(200, serde_json::json!({})))
+        .respond(json(200, serde_json::json!({})))
+        .respond(response(200, "deleted=org.chromium.linux"));
+    let result =
+        fcm_push_listener::unregister(&http(&transport), &registration, &firebase(), true).await;
+    assert!(result.is_complete(), "{result:?}");
+
+    let requests = transport.requests();
+    assert_eq!(requests[0].method, http::Method::DELETE);
+    assert_eq!(requests[0].uri, format!("{FCM_URL}/{FCM_TOKEN}"));
+    assert_eq!(requests[1].method, http::Method::DELETE);
+    assert_eq!(requests[1].uri, format!("{INSTALLATIONS_URL}/{FID}"));
+    assert_eq!(requests[2].uri, REGISTER_URL);
+
+    // credentials are kept out of transport logs
+    let sensitive = |request: &Recorded, name: &str| request.headers[name].is_sensitive();
+    assert!(sensitive(
+        &requests[0],
+        "x-goog-firebase-installations-auth"
+    ));
+    assert!(sensitive(&requests[1], "authorization"));
+    assert_eq!(
+        requests[1].header("authorization"),
+        Some("FIS_v2 2_refresh-token")
+    );
+    assert!(sensitive(&requests[2], "authorization"));
+    let form = requests[2].form();
+    assert!(form.contains(&("delete".to_owned(), "true".to_owned())));
+    assert!(form.contains(&(
+        "X-subtype".to_owned(),
+        registration.gcm_app_id.clone().unwrap()
+    )));
+
+    // Keeping the installation skips its deletion
+    let transport = FakeTransport::new();
+    transport
+        .respond(json(200, serde_json::json!({})))
+        .respond(response(200, "deleted=org.chromium.linux"));
+    let result =
+        fcm_push_listener::unregister(&http(&transport), &registration, &firebase(), false).await;
+    assert!(result.is_complete(), "{result:?}");
+    assert!(result.installation.is_none());
+    assert_eq!(transport.uris().len(), 2);
+}
+
+#[tokio::test]
+async fn unregistering_old_registrations_skips_what_they_lack() {
+    let transport = FakeTransport::new();
+    respond_to_registration(&transport);
+    let mut registration = fcm_push_listener::register(&http(&transport), &firebase())
+        .await
+        .unwrap();
+    registration.installation = None;
+    registration.gcm_app_id = None;
+
+    let transport = FakeTransport::new();
+    let result =
+        fcm_push_listener::unregister(&http(&transport), &registration, &firebase(), true).await;
+
+    assert!(matches!(result.fcm, Err(Error::InvalidRegistration(_))));
+    assert!(matches!(result.gcm, Err(Error::InvalidRegistration(_))));
+    assert!(result.installation.is_none());
+    assert!(transport.requests().is_empty());
+}
+
+#[tokio::test]
+async fn refreshes_token_for_same_device() {
+    let transport = FakeTransport::new();
+    respond_to_registration(&transport);
+    let mut registration = fcm_push_listener::register(&http(&transport), &firebase())
+        .await
+        .unwrap();
+    let gcm_app_id = registration.gcm_app_id.clone();
+
+    // The installation's auth token is still fresh, so only the tokens are requested again
+    let transport = FakeTransport::new();
+    transport
+        .respond(register3("cKp2o1mWRuo:APA91bG-new-gcm-token"))
+        .respond(fcm_registration("fE8aC1xXRR2:APA91bH-new-fcm-token"));
+    let changed = registration
+        .refresh(&http(&transport), &firebase())
+        .await
+        .unwrap();
+
+    assert!(changed);
+    assert_eq!(registration.fcm_token, "fE8aC1xXRR2:APA91bH-new-fcm-token");
+    assert_eq!(registration.gcm_app_id, gcm_app_id);
+    assert_eq!(registration.gcm.android_id as u64, ANDROID_ID);
+    assert_eq!(transport.uris(), [REGISTER_URL, FCM_URL]);
+}
+
+#[tokio::test]
+async fn registers_as_device_profile() {
+    use fcm_push_listener::{DeviceProfile, NetworkType, Platform};
+
+    let device = DeviceProfile::default()
+        .with_platform(Platform::Windows)
+        .with_network_type(NetworkType::Wifi)
+        .with_app("org.chromium.windows");
+
+    let transport = FakeTransport::new();
+    respond_to_registration(&transport);
+    let registration = RegistrationBuilder::new()
+        .with_device_profile(device.clone())
+        .finish(&http(&transport), &firebase())
+        .await
+        .unwrap();
+
+    let form = transport.requests()[1].form();
+    assert!(form.contains(&("app".to_owned(), "org.chromium.windows".to_owned())));
+
+    // The profile is kept with the session, old sessions get the default one
+    let saved = serde_json::to_value(&registration.gcm).unwrap();
+    let session: Session = serde_json::from_value(saved.clone()).unwrap();
+    assert_eq!(session.device, device);
+
+    let mut old = saved;
+    old.as_object_mut().unwrap().remove("device");
+    let session: Session = serde_json::from_value(old).unwrap();
+    assert_eq!(session.device, DeviceProfile::default());
+    assert_eq!(session.device.app(), "org.chromium.linux");
+}
+
+#[tokio::test]
+async fn checkin_hook_saves_changed_sessions() {
+    let transport = FakeTransport::new();
+    respond_to_registration(&transport);
+    let mut registration = fcm_push_listener::register(&http(&transport), &firebase())
+        .await
+        .unwrap();
+
+    // Unchanged credentials don't call the hook
+    transport.respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)));
+    registration
+        .checkin(&http(&transport), |_| -> Result<(), Error> {
+            panic!("nothing changed")
+        })
+        .await
+        .unwrap();
+
+    // A new security token does, with the updated registration
+    transport.respond(checkin(checkin_response(ANDROID_ID, 42)));
+    let mut saved = None;
+    registration
+        .checkin(&http(&transport), |updated| -> Result<(), Error> {
+            saved = Some(serde_json::to_string(updated).unwrap());
+            Ok(())
+        })
+        .await
+        .unwrap();
+    assert_eq!(registration.gcm.security_token, 42);
+    assert!(saved.unwrap().contains("42"));
+
+    // A failed save keeps the session from being handed out
+    #[derive(Debug)]
+    enum SaveError {
+        Disk,
+        Checkin(Error),
+    }
+    impl From<Error> for SaveError {
+        fn from(e: Error) -> Self {
+            Self::Checkin(e)
+        }
+    }
+
+    transport.respond(checkin(checkin_response(ANDROID_ID, 43)));
+    let result = registration
+        .checkin(&http(&transport), |_| Err(SaveError::Disk))
+        .await;
+    assert!(matches!(result, Err(SaveError::Disk)));
+    assert_eq!(registration.gcm.security_token, 42);
+
+    // so the next checkin hands the unsaved session to the hook again
+    transport.respond(checkin(checkin_response(ANDROID_ID, 43)));
+    let mut called = false;
+    registration
+        .checkin(&http(&transport), |updated| -> Result<(), SaveError> {
+            called = true;
+            assert_eq!(updated.gcm.security_token, 43);
+            Ok(())
+        })
+        .await
+        .unwrap();
+    assert!(called);
+    assert_eq!(registration.gcm.security_token, 43);
+
+    transport.respond(response(400, ""));
+    let result = registration
+        .checkin(&http(&transport), |_| Err(SaveError::Disk))
+        .await;
+    assert!(matches!(result, Err(SaveError::Checkin(Error::Api(_)))));
+}
+
+#[tokio::test]
+async fn debug_output_redacts_secrets() {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    let transport = FakeTransport::new();
+    respond_to_registration(&transport);
+    let registration = fcm_push_listener::register(&http(&transport), &firebase())
+        .await
+        .unwrap();
+
+    let debug = format!("{registration:?} {registration:#?}");
+    assert!(debug.contains(&ANDROID_ID.to_string()), "{debug}");
+    for secret in [
+        FCM_TOKEN.to_owned(),
+        SECURITY_TOKEN.to_string(),
+        "2_refresh-token".to_owned(),
+        "eyJhbGciOi.auth-token".to_owned(),
+    ] {
+        assert!(!debug.contains(&secret), "{secret} in {debug}");
+    }
+
+    let keys = &registration.keys;
+    for bytes in [&keys.private_key, &keys.auth_secret] {
+        assert!(!debug.contains(&URL_SAFE_NO_PAD.encode(bytes)), "{debug}");
+        let list = format!("{bytes:?}");
+        assert!(!debug.contains(&list[1..list.len() - 1]), "{debug}");
+    }
+}
@@ -0,0 +1,159 @@
+mod common;
+
+use common::*;
+use fcm_push_listener::{Error, Http, Installation, RetryPolicy, Session, TransportError};
+
+fn retry_after(status: u16, seconds: &str) -> http::Response<bytes::Bytes> {
+    let mut response = response(status, "");
+    response.headers_mut().insert(
+        http::header::RETRY_AFTER,
+        http::HeaderValue::from_str(seconds).unwrap(),
+    );
+    response
+}
+
+async fn session(transport: &FakeTransport) -> Session {
+    transport.respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)));
+    let session = Session::create(&http(transport)).await.unwrap();
+    assert_eq!(transport.remaining(), 0);
+    session
+}
+
+#[tokio::test]
+async fn retries_idempotent_calls() {
+    let transport = FakeTransport::new();
+    let session = session(&transport).await;
+
+    transport
+        .respond(response(503, "Service Unavailable"))
+        .fail(TransportError::retryable("timed out"))
+        .respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)));
+    session.checkin(&http(&transport)).await.unwrap();
+    assert_eq!(transport.requests().len(), 4);
+}
+
+#[tokio::test]
+async fn gives_up_after_max_attempts() {
+    let transport = FakeTransport::new();
+    let session = session(&transport).await;
+
+    transport
+        .respond(response(500, ""))
+        .respond(response(502, ""))
+        .respond(response(504, ""));
+    let error = session
+        .checkin(&http(&transport))
+        .await
+        .err()
+        .expect("checkin should fail");
+    let Error::Api(error) = error else {
+        panic!("expected an API error, got {error:?}");
+    };
+    assert_eq!(error.http_status, http::StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(transport.remaining(), 0);
+
+    // Without retries, the first failure is final
+    transport.respond(response(503, ""));
+    let http = Http::new(&transport).with_retry_policy(RetryPolicy::none());
+    session
+        .checkin(&http)
+        .await
+        .err()
+        .expect("checkin should fail");
+    assert_eq!(transport.remaining(), 0);
+}
+
+#[tokio::test]
+async fn does_not_retry_permanent_failures() {
+    let transport = FakeTransport::new();
+    let session = session(&transport).await;
+
+    transport
+        .respond(response(400, "Bad Request"))
+        .respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)));
+    session
+        .checkin(&http(&transport))
+        .await
+        .err()
+        .expect("checkin should fail");
+    assert_eq!(transport.remaining(), 1);
+}
+
+#[tokio::test]
+async fn follows_short_retry_after() {
+    let transport = FakeTransport::new();
+    let session = session(&transport).await;
+
+    transport
+        .respond(retry_after(429, "0"))
+        .respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)));
+    session.checkin(&http(&transport)).await.unwrap();
+    assert_eq!(transport.remaining(), 0);
+}
+
+#[tokio::test]
+async fn gives_up_on_long_retry_after() {
+    let transport = FakeTransport::new();
+    let session = session(&transport).await;
+
+    transport
+        .respond(retry_after(503, "86400"))
+        .respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)));
+    let started = std::time::Instant::now();
+    let error = session
+        .checkin(&http(&transport))
+        .await
+        .err()
+        .expect("checkin should fail");
+
+    assert!(matches!(error, Error::Api(_)), "{error:?}");
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(transport.remaining(), 1);
+}
+
+#[tokio::test]
+async fn retries_creation_only_when_not_received() {
+    let transport = FakeTransport::new();
+
+    // A timeout or a 500 may have created the installation already
+    transport.fail(TransportError::retryable("timed out"));
+    Installation::create(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+    transport.respond(response(500, ""));
+    Installation::create(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+    assert_eq!(transport.requests().len(), 2);
+
+    // A refused connection or a 503 means the server didn't act on it
+    transport
+        .fail(TransportError::not_sent("connection refused"))
+        .respond(response(503, ""))
+        .respond(installation());
+    let installation = Installation::create(&http(&transport), &firebase())
+        .await
+        .unwrap();
+    assert_eq!(installation.fid, FID);
+    assert_eq!(transport.requests().len(), 5);
+}
+
+#[tokio::test]
+async fn does_not_retry_fcm_registration_timeouts() {
+    let transport = FakeTransport::new();
+    transport
+        .respond(checkin(checkin_response(ANDROID_ID, SECURITY_TOKEN)))
+        .respond(register3(GCM_TOKEN))
+        .respond(installation())
+        .fail(TransportError::retryable("timed out"))
+        .respond(fcm_registration(FCM_TOKEN));
+
+    let error = fcm_push_listener::register(&http(&transport), &firebase())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, Error::Request("FCM Registration", _)),
+        "{error:?}"
+    );
+    assert_eq!(transport.remaining(), 1);
+}